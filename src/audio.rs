@@ -0,0 +1,122 @@
+//! Audio host, output device and stream configuration selection.
+//!
+//! cpal can drive several audio APIs (hosts) on the same machine, such as ALSA and JACK on Linux.
+//! These functions pick the host and device named on the command line, and find a stream
+//! configuration the device actually supports for the requested sample rate and buffer size.
+
+use crate::cli::AudioArgs;
+use crate::error::Error;
+use cpal::traits::{DeviceTrait, HostTrait};
+use cpal::{BufferSize, Device, Host, SampleRate, SupportedBufferSize, SupportedStreamConfig};
+
+/// The `--host` audio host, or the platform default.
+pub fn choose_host(audio: &AudioArgs) -> Result<Host, Error> {
+    let Some(name) = &audio.host else {
+        return Ok(cpal::default_host());
+    };
+    let hosts = cpal::available_hosts();
+    let Some(id) = hosts.iter().find(|id| id.name().eq_ignore_ascii_case(name)) else {
+        return Err(Error::UnknownHost {
+            name: name.clone(),
+            available: hosts.iter().map(|id| id.name()).collect(),
+        });
+    };
+    Ok(cpal::host_from_id(*id)?)
+}
+
+/// The first output device of `host` whose name contains `--device` (case-insensitive), or the
+/// host's default output device.
+pub fn choose_device(host: &Host, audio: &AudioArgs) -> Result<Device, Error> {
+    match &audio.device {
+        Some(name) => {
+            let pattern = name.to_lowercase();
+            host.output_devices()?
+                .find(|d| d.name().is_ok_and(|n| n.to_lowercase().contains(&pattern)))
+                .ok_or_else(|| Error::NoMatchingDevice(name.clone()))
+        }
+        None => host.default_output_device().ok_or(Error::NoDefaultDevice),
+    }
+}
+
+/// A stream configuration for `device` at `--sample-rate` with a `--buffer-size` frame buffer.
+/// Without a sample rate the device's default configuration is used; with one, the supported
+/// configuration closest to the default (same sample format, then same channel count) is chosen.
+pub fn choose_config(device: &Device, audio: &AudioArgs) -> Result<SupportedStreamConfig, Error> {
+    let default = device.default_output_config()?;
+    let config = match audio.sample_rate {
+        None => default,
+        Some(rate) => {
+            let rate = SampleRate(rate);
+            let mut candidates: Vec<_> = device
+                .supported_output_configs()?
+                .filter(|c| c.min_sample_rate() <= rate && rate <= c.max_sample_rate())
+                .collect();
+            candidates.sort_by_key(|c| {
+                (
+                    c.sample_format() != default.sample_format(),
+                    c.channels() != default.channels(),
+                )
+            });
+            let Some(config) = candidates.into_iter().next() else {
+                return Err(Error::UnsupportedSampleRate(rate.0));
+            };
+            config.with_sample_rate(rate)
+        }
+    };
+    if let (Some(frames), SupportedBufferSize::Range { min, max }) =
+        (audio.buffer_size, config.buffer_size())
+    {
+        if frames < *min || frames > *max {
+            return Err(Error::UnsupportedBufferSize {
+                frames,
+                min: *min,
+                max: *max,
+            });
+        }
+    }
+    Ok(config)
+}
+
+/// The buffer size to request from the device: `--buffer-size` frames, or the device default.
+pub fn buffer_size(audio: &AudioArgs) -> BufferSize {
+    audio
+        .buffer_size
+        .map_or(BufferSize::Default, BufferSize::Fixed)
+}
+
+/// Print every available host with its output devices and the configurations they support.
+pub fn print_devices() -> Result<(), Error> {
+    let default_host = cpal::default_host().id();
+    for id in cpal::available_hosts() {
+        let marker = if id == default_host { " (default)" } else { "" };
+        println!("Audio host {}{marker}:", id.name());
+        let host = cpal::host_from_id(id)?;
+        let default = host.default_output_device().and_then(|d| d.name().ok());
+        for (index, device) in host.output_devices()?.enumerate() {
+            let name = device.name().unwrap_or_else(|_| "(unknown)".into());
+            let marker = if Some(&name) == default.as_ref() {
+                " (default)"
+            } else {
+                ""
+            };
+            println!("  {index}: {name}{marker}");
+            let Ok(configs) = device.supported_output_configs() else {
+                continue;
+            };
+            for config in configs {
+                let buffer = match config.buffer_size() {
+                    SupportedBufferSize::Range { min, max } => format!("{min}-{max} frames"),
+                    SupportedBufferSize::Unknown => "unknown buffer size".into(),
+                };
+                println!(
+                    "       {} ch, {}, {}-{} Hz, {buffer}",
+                    config.channels(),
+                    config.sample_format(),
+                    config.min_sample_rate().0,
+                    config.max_sample_rate().0,
+                );
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,260 @@
+//! Bidirectional digital waveguide string.
+//!
+//! The single loop of `create_sound()` lumps the whole string into one delay, which is enough to
+//! set its pitch but leaves no place along it to pluck or listen. Here the string is two delay
+//! lines, or rails, of half a period each: one carrying the wave from the nut to the bridge and
+//! the other carrying it back. The ends reflect the wave inverted; the nut is rigid, while the
+//! bridge moves a little and so applies the `LoopFilter` loss and the `Dispersion` of the whole
+//! round trip. The nut reflection is a bare inversion on purpose: the loss filter is fitted to the
+//! decay times of the whole string, so it already accounts for what the nut and the string itself
+//! lose, and filtering at both ends would make the string die away twice as fast. Lumping the loss
+//! at one end changes nothing that can be heard at the pickups, as every wave passes both ends
+//! once per trip. The displacement anywhere along the string is the sum of the two rails at that
+//! point, so pickups can tap it where their coils sit, and noise and hammer excitations are driven
+//! into both rails at the pluck position, where the reflections off each end produce the comb
+//! filtering by themselves.
+
+use crate::dispersion::{dispersion, Dispersion};
+use crate::excitation::Excitation;
+use crate::expression::{damper, Damper};
+use crate::loss::{loop_filter, LoopFilter, LossFilter};
+use crate::string::StringModel;
+use crate::variant::{sign_flip, SignFlip};
+use crate::waveguide::{filter_delay, lagrange, linear};
+use fundsp::hacker::*;
+
+/// Which waveguide the strings are built from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Waveguide {
+    /// A single delay loop, the classic Karplus-Strong string.
+    Single,
+    /// Two rails travelling in opposite directions, with reflections at the nut and bridge.
+    Bidirectional,
+}
+
+/// A string made of two delay rails, excited at the pluck position and tapped at the pickups.
+#[derive(Clone)]
+pub struct Rails {
+    excitation: Excitation,
+    loss: LossFilter,
+    string: StringModel,
+    bridge: LoopFilter,
+    stiffness: Dispersion,
+    flip: SignFlip,
+    damper: Damper,
+    // distance of each tap from the bridge (s of travel); no taps means listening at the bridge
+    taps: [f64; 2],
+    tap_count: usize,
+    max_delay: f64,
+    sample_rate: f64,
+    // waves travelling towards the bridge and towards the nut
+    right: Vec<f64>,
+    left: Vec<f64>,
+    mask: usize,
+    write: usize,
+    // fundamental the rails are tuned to, and the resulting length of each rail (samples)
+    freq: f64,
+    length: f64,
+}
+
+impl Rails {
+    /// Read `rail` `delay` samples before its newest sample, interpolating linearly.
+    #[inline]
+    fn read(&self, rail: &[f64], delay: f64) -> f64 {
+        linear(delay, |i| rail[(self.write.wrapping_sub(i)) & self.mask])
+    }
+
+    /// Displacement of the string `distance` samples from the bridge: the wave on its way to the
+    /// bridge plus the wave on its way back.
+    #[inline]
+    fn displacement(&self, distance: f64) -> f64 {
+        let distance = clamp(0.0, self.length, distance);
+        self.read(&self.right, self.length - distance) + self.read(&self.left, distance)
+    }
+
+    /// Retune the rails so a round trip, including the delay of the bridge filters, lasts one
+    /// period of `freq`.
+    fn tune(&mut self, freq: f64) {
+        self.freq = freq;
+        let filter_delay = filter_delay(&self.loss, &self.string, freq, self.sample_rate);
+        let length = 0.5 * (self.sample_rate / freq - filter_delay) - 1.0;
+        self.length = clamp(1.0, self.max_delay * self.sample_rate, length);
+    }
+}
+
+impl AudioNode for Rails {
+    const ID: u64 = 1007;
+    type Sample = f64;
+    type Inputs = U4;
+    type Outputs = U1;
+    type Setting = ();
+
+    fn reset(&mut self, sample_rate: Option<f64>) {
+        if let Some(sample_rate) = sample_rate {
+            self.sample_rate = sample_rate;
+            // room for the longest rail plus the extra points read by the interpolator
+            let length = (self.max_delay * sample_rate).ceil() as usize + 8;
+            self.right = vec![0.0; length.next_power_of_two()];
+            self.left = vec![0.0; length.next_power_of_two()];
+            self.mask = self.right.len() - 1;
+            // retune on the next sample
+            self.freq = 0.0;
+        }
+        self.bridge.reset(sample_rate);
+        self.stiffness.reset(sample_rate);
+        self.flip.reset(sample_rate);
+        self.damper.reset(sample_rate);
+        self.right.fill(0.0);
+        self.left.fill(0.0);
+        self.write = 0;
+    }
+
+    #[inline]
+    fn tick(&mut self, input: &Frame<f64, U4>) -> Frame<f64, U1> {
+        if input[1] != self.freq {
+            self.tune(input[1]);
+        }
+
+        // the waves arriving at each end are reflected, inverted, onto the other rail; the loss of
+        // the whole round trip is applied at the bridge, so the nut reflects without loss
+        let at = |rail: &[f64], i: usize| rail[(self.write.wrapping_sub(i)) & self.mask];
+        let at_bridge = lagrange::<4>(self.length, 1.0, |i| at(&self.right, i));
+        let at_nut = lagrange::<4>(self.length, 1.0, |i| at(&self.left, i));
+        let lost = self.bridge.tick(&[at_bridge, self.freq].into())[0];
+        let dispersed = self.stiffness.tick(&[lost, self.freq].into())[0];
+        let flipped = self.flip.tick(&[dispersed].into())[0];
+        let reflected = self.damper.tick(&[flipped, self.freq, input[3]].into())[0];
+        self.write = (self.write + 1) & self.mask;
+        self.right[self.write] = -at_nut;
+        self.left[self.write] = -reflected;
+
+        // a displacement burst already covers the whole loop, so it enters at the nut; anything
+        // else is driven into both rails at the pluck position
+        if self.excitation.is_displacement() {
+            self.right[self.write] += input[0];
+        } else {
+            let length = self.length.round() as usize;
+            let distance = (clamp(0.0, 1.0, input[2]) * length as f64).round() as usize;
+            let to_bridge = (self.write.wrapping_sub(length - distance)) & self.mask;
+            let to_nut = (self.write.wrapping_sub(distance)) & self.mask;
+            self.right[to_bridge] += 0.5 * input[0];
+            self.left[to_nut] += 0.5 * input[0];
+        }
+
+        let output = match self.tap_count {
+            0 => at_bridge,
+            count => {
+                let mut sum = 0.0;
+                for tap in &self.taps[..count] {
+                    sum += self.displacement(tap * self.sample_rate);
+                }
+                sum / count as f64
+            }
+        };
+        [output].into()
+    }
+}
+
+/// A bidirectional waveguide for `string`, with the same loss and dispersion as the single loop,
+/// whose bridge keeps the sign of the wave with probability `blend` (see `sign_flip()`). The rails
+/// are up to `max_delay` seconds long. The string is heard at the bridge, or through `taps`, the
+/// distances from the bridge (as fractions of the open string length) at which it is read and
+/// averaged.
+/// - Input 0: excitation
+/// - Input 1: fundamental (Hz)
+/// - Input 2: pluck position, as a fraction of the vibrating length from the bridge
+/// - Input 3: damping (0 to 1), from `VoiceControls::damping` and the release of the note
+/// - Output 0: string
+pub fn bidirectional(
+    string: &StringModel,
+    loss: LossFilter,
+    excitation: Excitation,
+    taps: &[f64],
+    blend: f64,
+    max_delay: f64,
+) -> An<Rails> {
+    // taps are fixed in place along the string, whichever fret it is stopped at
+    let one_way = 0.5 * string.round_trip();
+    let mut tap_times = [0.0; 2];
+    let tap_count = Ord::min(taps.len(), tap_times.len());
+    for (time, tap) in tap_times.iter_mut().zip(taps) {
+        *time = tap * one_way;
+    }
+    let mut node = Rails {
+        excitation,
+        loss,
+        string: *string,
+        bridge: loop_filter(loss, string).0,
+        stiffness: dispersion(string).0,
+        flip: sign_flip(blend).0,
+        damper: damper().0,
+        taps: tap_times,
+        tap_count,
+        max_delay,
+        sample_rate: DEFAULT_SR,
+        right: vec![],
+        left: vec![],
+        mask: 0,
+        write: 0,
+        freq: 0.0,
+        length: 1.0,
+    };
+    node.reset(Some(DEFAULT_SR));
+    An(node)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static SAMPLE_RATE: f64 = 44100.0;
+
+    // An open string at 110 Hz, read through `taps`.
+    fn rails(taps: &[f64]) -> An<Rails> {
+        let string = StringModel::tuned(110.0, 0.0004, 0.648);
+        let mut rails = bidirectional(
+            &string,
+            LossFilter::OnePole,
+            Excitation::Hammer,
+            taps,
+            1.0,
+            0.125,
+        );
+        rails.reset(Some(SAMPLE_RATE));
+        rails
+    }
+
+    // Half a second of `rails` ringing at `freq` after an impulse a fifth of the way from the
+    // bridge, leaving out the first 50 ms.
+    fn ring(mut rails: An<Rails>, freq: f64) -> Vec<f64> {
+        (0..(0.55 * SAMPLE_RATE) as usize)
+            .map(|i| {
+                let impulse = if i == 0 { 1.0 } else { 0.0 };
+                rails.tick(&[impulse, freq, 0.2, 0.0].into())[0]
+            })
+            .skip((0.05 * SAMPLE_RATE) as usize)
+            .collect()
+    }
+
+    #[test]
+    fn rails_ring_at_the_fundamental() {
+        for freq in [110.0, 146.8, 246.9, 440.0] {
+            let measured = crate::measure_frequency(&ring(rails(&[]), freq), freq, SAMPLE_RATE);
+            let cents = 1200.0 * (measured / freq).log2();
+            assert!(cents.abs() < 0.5, "{freq} Hz is {cents:+.2} cents off");
+        }
+    }
+
+    #[test]
+    fn taps_read_the_string_where_they_sit() {
+        // the 2nd harmonic has a node in the middle of the string, and moves 0.3 from the bridge
+        let harmonics = |tap| {
+            let samples = ring(rails(&[tap]), 110.0);
+            [1.0, 2.0, 3.0].map(|n| crate::amplitude(&samples, 110.0 * n, SAMPLE_RATE))
+        };
+        let [first, second, third] = harmonics(0.5);
+        assert!(second < 0.05 * first && second < 0.05 * third);
+        let [first, second, _] = harmonics(0.3);
+        assert!(second > 0.5 * first);
+    }
+}
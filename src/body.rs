@@ -0,0 +1,457 @@
+//! Instrument body.
+//!
+//! A bare string moves very little air: most of what is heard from an acoustic instrument is
+//! radiated by its body, whose top plate and enclosed air are driven through the bridge and add
+//! resonances of their own. `apply_body()` passes the mix of every string through a single
+//! body, which is either a bank of resonators tuned to the main modes of a preset instrument, or
+//! the measured impulse response of a real one.
+//!
+//! An impulse response can be seconds long, far too long to convolve sample by sample. `Convolver`
+//! splits it into blocks and convolves each one with FFTs (uniformly partitioned overlap-save),
+//! which costs a few operations per sample whatever the length, at the price of one block of
+//! latency. Everything it needs is allocated up front, so nothing is allocated on the audio thread.
+
+use anyhow::{bail, Context};
+use fundsp::hacker::*;
+use fundsp::prelude::AudioUnit64;
+use hound::{SampleFormat, WavReader};
+use rustfft::num_complex::Complex64;
+use rustfft::{Fft, FftPlanner};
+use std::f64::consts::PI;
+use std::path::Path;
+use std::sync::Arc;
+
+// Length of each partition of the impulse response (samples), which is also the latency of the
+// convolution.
+static BLOCK: usize = 256;
+
+// Longest impulse response used (s). Anything after this is well below the noise floor of a
+// measured body.
+static MAX_RESPONSE: f64 = 2.0;
+
+/// A body built from the resonant modes of an instrument.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum BodyPreset {
+    /// A dreadnought acoustic guitar: a strong air resonance around 100 Hz and a warm top.
+    Guitar,
+    /// A soprano ukulele: a small body, so its resonances are higher and it has little bass.
+    Ukulele,
+    /// A banjo: a taut drum head with many short, bright resonances and no air cavity to speak of.
+    Banjo,
+}
+
+impl BodyPreset {
+    /// Frequency (Hz), decay time (s) and gain of each mode: the air resonance of the cavity and
+    /// the main top plate (or head) modes.
+    fn modes(&self) -> &'static [(f64, f64, f64)] {
+        match self {
+            BodyPreset::Guitar => &[
+                (102.0, 0.25, 1.0),
+                (204.0, 0.15, 0.8),
+                (390.0, 0.10, 0.45),
+                (550.0, 0.08, 0.35),
+                (820.0, 0.05, 0.25),
+                (1250.0, 0.04, 0.15),
+            ],
+            BodyPreset::Ukulele => &[
+                (260.0, 0.12, 0.9),
+                (420.0, 0.10, 1.0),
+                (660.0, 0.07, 0.5),
+                (1050.0, 0.05, 0.3),
+                (1600.0, 0.03, 0.2),
+            ],
+            BodyPreset::Banjo => &[
+                (310.0, 0.08, 0.6),
+                (620.0, 0.06, 0.7),
+                (1050.0, 0.05, 0.6),
+                (1700.0, 0.04, 0.5),
+                (2600.0, 0.03, 0.45),
+                (3600.0, 0.02, 0.35),
+            ],
+        }
+    }
+
+    /// Gain of the string heard directly, alongside the resonances.
+    fn direct(&self) -> f64 {
+        match self {
+            BodyPreset::Guitar => 0.4,
+            BodyPreset::Ukulele => 0.5,
+            BodyPreset::Banjo => 0.7,
+        }
+    }
+}
+
+/// A mono impulse response and the sample rate it was recorded at (Hz).
+#[derive(Clone, Debug, PartialEq)]
+pub struct ImpulseResponse {
+    samples: Vec<f64>,
+    sample_rate: f64,
+}
+
+impl ImpulseResponse {
+    /// Read an impulse response from the WAV file at `path`, for parsing `--body-ir`.
+    pub fn parse(path: &str) -> anyhow::Result<Self> {
+        Self::load(Path::new(path))
+    }
+
+    /// Read an impulse response from a WAV file, mixing its channels down to mono.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let mut reader = WavReader::open(path)
+            .with_context(|| format!("Failed to open impulse response {}", path.display()))?;
+        let spec = reader.spec();
+        let samples: Vec<f64> = match spec.sample_format {
+            SampleFormat::Float => reader
+                .samples::<f32>()
+                .map(|s| s.map(|s| s as f64))
+                .collect::<Result<_, _>>()?,
+            SampleFormat::Int => {
+                let scale = (1_i64 << (spec.bits_per_sample - 1)) as f64;
+                reader
+                    .samples::<i32>()
+                    .map(|s| s.map(|s| s as f64 / scale))
+                    .collect::<Result<_, _>>()?
+            }
+        };
+        let channels = Ord::max(spec.channels as usize, 1);
+        let samples: Vec<f64> = samples
+            .chunks(channels)
+            .map(|frame| frame.iter().sum::<f64>() / channels as f64)
+            .collect();
+        if samples.iter().all(|s| *s == 0.0) {
+            bail!("Impulse response {} is silent", path.display());
+        }
+        Ok(Self {
+            samples,
+            sample_rate: spec.sample_rate as f64,
+        })
+    }
+
+    /// The response at `sample_rate`, resampled linearly if it was recorded at another rate, cut
+    /// to `MAX_RESPONSE` and scaled to unit energy so the body doesn't change the overall level.
+    fn at_rate(&self, sample_rate: f64) -> Vec<f64> {
+        let step = self.sample_rate / sample_rate;
+        let length = (self.samples.len() as f64 / step).ceil() as usize;
+        let length = Ord::min(length, (MAX_RESPONSE * sample_rate) as usize);
+        let at = |i: usize| self.samples.get(i).copied().unwrap_or(0.0);
+        let mut response: Vec<f64> = (0..length)
+            .map(|i| {
+                let position = i as f64 * step;
+                let whole = position.floor();
+                let frac = position - whole;
+                at(whole as usize) * (1.0 - frac) + at(whole as usize + 1) * frac
+            })
+            .collect();
+        let energy = response.iter().map(|s| s * s).sum::<f64>().sqrt();
+        if energy > 0.0 {
+            response.iter_mut().for_each(|s| *s /= energy);
+        }
+        response
+    }
+}
+
+/// Pass the `mix` of the strings through the body: convolved with the measured `response` if
+/// there is one, otherwise through the modes of `preset`, or not at all.
+pub fn apply_body(
+    mix: Box<dyn AudioUnit64>,
+    preset: Option<BodyPreset>,
+    response: Option<&ImpulseResponse>,
+) -> Box<dyn AudioUnit64> {
+    let body: Box<dyn AudioUnit64> = match (preset, response) {
+        (_, Some(response)) => Box::new(convolver(response)),
+        (Some(preset), None) => Box::new(modal_body(preset)),
+        (None, None) => return mix,
+    };
+    Box::new(Net64::wrap(mix) >> Net64::wrap(body))
+}
+
+/// A bank of two-pole resonators, one per mode of a `BodyPreset`, summed with the direct sound.
+#[derive(Clone)]
+pub struct ModalBody {
+    preset: BodyPreset,
+    // gain, and the two feedback coefficients, of each resonator
+    coefficients: Vec<(f64, f64, f64)>,
+    // previous two outputs of each resonator, and the previous two inputs they share
+    outputs: Vec<(f64, f64)>,
+    x1: f64,
+    x2: f64,
+}
+
+impl AudioNode for ModalBody {
+    const ID: u64 = 1010;
+    type Sample = f64;
+    type Inputs = U1;
+    type Outputs = U1;
+    type Setting = ();
+
+    fn reset(&mut self, sample_rate: Option<f64>) {
+        if let Some(sample_rate) = sample_rate {
+            // modes above the Nyquist frequency can't be heard, and would be unstable
+            self.coefficients = self
+                .preset
+                .modes()
+                .iter()
+                .filter(|(freq, _, _)| *freq < 0.45 * sample_rate)
+                .map(|(freq, decay_time, gain)| {
+                    let radius = 10.0_f64.powf(-3.0 / (decay_time * sample_rate));
+                    let omega = 2.0 * PI * freq / sample_rate;
+                    // the zeros at DC and Nyquist bring the peak gain close to 1
+                    let normalize = 0.5 * (1.0 - radius * radius);
+                    (
+                        gain * normalize,
+                        2.0 * radius * omega.cos(),
+                        -radius * radius,
+                    )
+                })
+                .collect();
+        }
+        self.outputs = vec![(0.0, 0.0); self.coefficients.len()];
+        self.x1 = 0.0;
+        self.x2 = 0.0;
+    }
+
+    #[inline]
+    fn tick(&mut self, input: &Frame<f64, U1>) -> Frame<f64, U1> {
+        let x = input[0];
+        let mut sum = self.preset.direct() * x;
+        for ((gain, a1, a2), (y1, y2)) in self.coefficients.iter().zip(self.outputs.iter_mut()) {
+            let y = gain * (x - self.x2) + a1 * *y1 + a2 * *y2;
+            *y2 = *y1;
+            *y1 = y;
+            sum += y;
+        }
+        self.x2 = self.x1;
+        self.x1 = x;
+        [sum].into()
+    }
+}
+
+/// The body of `preset`, as a bank of resonators.
+/// - Input 0: strings
+/// - Output 0: strings through the body
+pub fn modal_body(preset: BodyPreset) -> An<ModalBody> {
+    let mut node = ModalBody {
+        preset,
+        coefficients: vec![],
+        outputs: vec![],
+        x1: 0.0,
+        x2: 0.0,
+    };
+    node.reset(Some(DEFAULT_SR));
+    An(node)
+}
+
+/// Convolves its input with an impulse response in blocks of `BLOCK` samples, using FFTs.
+#[derive(Clone)]
+pub struct Convolver {
+    response: ImpulseResponse,
+    fft: Arc<dyn Fft<f64>>,
+    ifft: Arc<dyn Fft<f64>>,
+    // spectrum of each partition of the response, and of the input for as many blocks back
+    partitions: Vec<Vec<Complex64>>,
+    spectra: Vec<Vec<Complex64>>,
+    // which of `spectra` holds the newest block
+    newest: usize,
+    // the last two blocks of input, the newest being filled; and the output of the last block
+    input: Vec<f64>,
+    output: Vec<f64>,
+    position: usize,
+    buffer: Vec<Complex64>,
+    scratch: Vec<Complex64>,
+}
+
+impl Convolver {
+    /// Convolve the two blocks in `input` with the whole response, leaving the result for the
+    /// newest block in `output`.
+    fn process(&mut self) {
+        let size = 2 * BLOCK;
+        for (bin, x) in self.buffer.iter_mut().zip(&self.input) {
+            *bin = Complex64::new(*x, 0.0);
+        }
+        self.fft
+            .process_with_scratch(&mut self.buffer, &mut self.scratch);
+        let count = self.spectra.len();
+        self.newest = (self.newest + count - 1) % count;
+        self.spectra[self.newest].copy_from_slice(&self.buffer);
+
+        // each partition of the response meets the input block it lines up with
+        self.buffer.fill(Complex64::new(0.0, 0.0));
+        for (p, partition) in self.partitions.iter().enumerate() {
+            let spectrum = &self.spectra[(self.newest + p) % count];
+            for ((bin, x), h) in self.buffer.iter_mut().zip(spectrum).zip(partition) {
+                *bin += x * h;
+            }
+        }
+        self.ifft
+            .process_with_scratch(&mut self.buffer, &mut self.scratch);
+
+        // the first half wrapped around, the second half is the output of the block
+        for (y, bin) in self.output.iter_mut().zip(&self.buffer[BLOCK..]) {
+            *y = bin.re / size as f64;
+        }
+        self.input.copy_within(BLOCK.., 0);
+    }
+}
+
+impl AudioNode for Convolver {
+    const ID: u64 = 1011;
+    type Sample = f64;
+    type Inputs = U1;
+    type Outputs = U1;
+    type Setting = ();
+
+    fn reset(&mut self, sample_rate: Option<f64>) {
+        let size = 2 * BLOCK;
+        if let Some(sample_rate) = sample_rate {
+            let mut planner = FftPlanner::new();
+            self.fft = planner.plan_fft_forward(size);
+            self.ifft = planner.plan_fft_inverse(size);
+            let scratch = Ord::max(
+                self.fft.get_inplace_scratch_len(),
+                self.ifft.get_inplace_scratch_len(),
+            );
+            self.scratch = vec![Complex64::new(0.0, 0.0); scratch];
+            self.partitions = self
+                .response
+                .at_rate(sample_rate)
+                .chunks(BLOCK)
+                .map(|chunk| {
+                    let mut partition = vec![Complex64::new(0.0, 0.0); size];
+                    for (bin, h) in partition.iter_mut().zip(chunk) {
+                        *bin = Complex64::new(*h, 0.0);
+                    }
+                    self.fft.process(&mut partition);
+                    partition
+                })
+                .collect();
+            self.spectra = vec![vec![Complex64::new(0.0, 0.0); size]; self.partitions.len()];
+            self.buffer = vec![Complex64::new(0.0, 0.0); size];
+            self.input = vec![0.0; size];
+            self.output = vec![0.0; BLOCK];
+        }
+        self.spectra
+            .iter_mut()
+            .for_each(|spectrum| spectrum.fill(Complex64::new(0.0, 0.0)));
+        self.input.fill(0.0);
+        self.output.fill(0.0);
+        self.newest = 0;
+        self.position = 0;
+    }
+
+    #[inline]
+    fn tick(&mut self, input: &Frame<f64, U1>) -> Frame<f64, U1> {
+        self.input[BLOCK + self.position] = input[0];
+        let output = self.output[self.position];
+        self.position += 1;
+        if self.position == BLOCK {
+            self.process();
+            self.position = 0;
+        }
+        [output].into()
+    }
+}
+
+/// Convolve with `response`, delayed by `BLOCK` samples.
+/// - Input 0: strings
+/// - Output 0: strings through the body
+pub fn convolver(response: &ImpulseResponse) -> An<Convolver> {
+    let mut planner = FftPlanner::new();
+    let mut node = Convolver {
+        response: response.clone(),
+        fft: planner.plan_fft_forward(2 * BLOCK),
+        ifft: planner.plan_fft_inverse(2 * BLOCK),
+        partitions: vec![],
+        spectra: vec![],
+        newest: 0,
+        input: vec![],
+        output: vec![],
+        position: 0,
+        buffer: vec![],
+        scratch: vec![],
+    };
+    node.reset(Some(DEFAULT_SR));
+    An(node)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn modal_bodies_ring_at_their_modes_and_die_away() {
+        let sample_rate = 44100.0;
+        for preset in [BodyPreset::Guitar, BodyPreset::Ukulele, BodyPreset::Banjo] {
+            let mut node = modal_body(preset);
+            node.reset(Some(sample_rate));
+            let response: Vec<f64> = (0..sample_rate as usize)
+                .map(|i| node.tick(&[if i == 0 { 1.0 } else { 0.0 }].into())[0])
+                .collect();
+            assert!(response.iter().all(|x| x.is_finite()));
+
+            // every mode has died away by 60 dB within a quarter of a second
+            let quarter = sample_rate as usize / 4;
+            let (early, late) = (
+                crate::peak(&response[1..quarter]),
+                crate::peak(&response[3 * quarter..]),
+            );
+            assert!(
+                late < 1.0e-3 * early,
+                "{preset:?} falls from {early} to {late}"
+            );
+
+            // each mode peaks within a quarter tone of it, and stands out from the spectrum a third
+            // of an octave either side
+            let spectrum = &response[..2 * quarter];
+            let level = |freq: f64| crate::amplitude(spectrum, freq, sample_rate);
+            let (quarter_tone, third) = (2.0_f64.powf(1.0 / 24.0), 2.0_f64.powf(1.0 / 3.0));
+            for (freq, _, _) in preset.modes() {
+                let at = level(*freq);
+                assert!(
+                    at > level(freq / quarter_tone).max(level(freq * quarter_tone))
+                        && at > 2.0 * level(freq / third).max(level(freq * third)),
+                    "{preset:?} has no resonance at {freq} Hz"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn convolver_matches_direct_convolution() {
+        let sample_rate = 44100.0;
+        // a response more than two partitions long, and a signal long enough to fill all of them
+        let response = ImpulseResponse {
+            samples: (0..2 * BLOCK + 100)
+                .map(|i| (rnd(i as i64) - 0.5) * (-(i as f64) / 200.0).exp())
+                .collect(),
+            sample_rate,
+        };
+        let h = response.at_rate(sample_rate);
+        let x: Vec<f64> = (0..5 * BLOCK)
+            .map(|i| rnd(1_000_000 + i as i64) - 0.5)
+            .collect();
+
+        let mut node = convolver(&response);
+        node.reset(Some(sample_rate));
+        // the input arrives in blocks that don't line up with the partitions
+        let mut y = vec![0.0; x.len()];
+        let mut start = 0;
+        for size in [1, 37, 64, 13].iter().cycle() {
+            let end = Ord::min(start + size, x.len());
+            node.process(end - start, &[&x[start..end]], &mut [&mut y[start..end]]);
+            start = end;
+            if start == x.len() {
+                break;
+            }
+        }
+
+        for (n, y) in y.iter().enumerate() {
+            let expected: f64 = (0..h.len())
+                .filter(|k| n >= BLOCK + k)
+                .map(|k| h[k] * x[n - BLOCK - k])
+                .sum();
+            assert!(
+                (y - expected).abs() < 1.0e-9,
+                "sample {n}: convolved {y}, expected {expected}"
+            );
+        }
+    }
+}
@@ -0,0 +1,274 @@
+//! Controller state of each MIDI channel.
+//!
+//! Every channel has its own pitch bend, and its own bend range, which a controller sets through
+//! Registered Parameter Number 0 (pitch bend sensitivity): CC 101 and 100 select the parameter,
+//! data entry (CC 6 and 38) then sets the range in semitones and cents, and data increment and
+//! decrement (CC 96 and 97) step it up or down by a cent. The voices started on
+//! a channel follow its bend, and its channel aftertouch, timbre (CC 74) and pluck position (CC
+//! 70). Registered Parameter Number 6 is the MPE Configuration Message that sets up the zones of
+//! the `mpe` module.
+
+/// Bend range of every channel until a controller sets it (semitones).
+pub static DEFAULT_BEND_RANGE: f64 = 2.0;
+
+/// Timbre of every channel until CC 74 sets it: the tone is fully open.
+pub static DEFAULT_TIMBRE: f64 = 1.0;
+
+/// Where notes are plucked until told otherwise, as a fraction of the string length from the
+/// bridge.
+pub static DEFAULT_PLUCK_POSITION: f64 = 0.2;
+
+// Centre of the 14-bit pitch bend wheel.
+static BEND_CENTRE: u16 = 8192;
+
+// Controllers that select and set registered and non-registered parameters. They are matched on,
+// so they are constants.
+const DATA_ENTRY: u8 = 6;
+const TIMBRE: u8 = 74;
+const DATA_ENTRY_FINE: u8 = 38;
+const DATA_INCREMENT: u8 = 96;
+const DATA_DECREMENT: u8 = 97;
+const NRPN_FINE: u8 = 98;
+const NRPN_COARSE: u8 = 99;
+const RPN_FINE: u8 = 100;
+const RPN_COARSE: u8 = 101;
+
+// The registered parameters for pitch bend sensitivity and MPE configuration, and the "null"
+// parameter that deselects them, as (coarse, fine) numbers.
+static PITCH_BEND_SENSITIVITY: (u8, u8) = (0, 0);
+static MPE_CONFIGURATION: (u8, u8) = (0, 6);
+static RPN_NULL: (u8, u8) = (127, 127);
+
+/// What a controller changed on a channel.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Change {
+    /// The bend range.
+    BendRange,
+    /// The timbre.
+    Timbre,
+    /// The number of member channels of the MPE zone the channel manages.
+    Zone(u8),
+}
+
+/// Pitch bend, bend range, aftertouch, timbre and pluck position of one channel.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ChannelState {
+    bend: u16,
+    /// Channel aftertouch (0 to 1).
+    pub pressure: f64,
+    /// Timbre (0 to 1), as set by CC 74.
+    pub timbre: f64,
+    /// Where the notes of the channel are plucked, as a fraction of the string length from the
+    /// bridge, as set by CC 70.
+    pub pluck_position: f64,
+    // bend range, set as whole semitones and cents
+    semitones: f64,
+    cents: f64,
+    // registered parameter selected for data entry, as (coarse, fine) numbers
+    parameter: Option<(u8, u8)>,
+}
+
+impl ChannelState {
+    /// A channel with its bend wheel centred and a bend range of `range` semitones.
+    pub fn new(range: f64) -> Self {
+        Self {
+            bend: BEND_CENTRE,
+            pressure: 0.0,
+            timbre: DEFAULT_TIMBRE,
+            pluck_position: DEFAULT_PLUCK_POSITION,
+            semitones: range,
+            cents: 0.0,
+            parameter: None,
+        }
+    }
+
+    /// The factor the pitch of the channel's notes is bent by.
+    pub fn bend_factor(&self) -> f64 {
+        pitch_bend_factor(self.bend, self.bend_range())
+    }
+
+    /// The bend range (semitones).
+    pub fn bend_range(&self) -> f64 {
+        self.semitones + self.cents / 100.0
+    }
+
+    /// Bend by up to `range` semitones either way.
+    pub fn set_bend_range(&mut self, range: f64) {
+        self.semitones = range;
+        self.cents = 0.0;
+    }
+
+    /// Move the bend wheel to `bend` (0 to 16383).
+    pub fn pitch_bend(&mut self, bend: u16) {
+        self.bend = bend;
+    }
+
+    /// Centre the bend wheel, take the aftertouch back to rest and deselect the parameter, as on
+    /// Reset All Controllers. The bend range, the timbre and the pluck position are kept.
+    pub fn reset(&mut self) {
+        self.bend = BEND_CENTRE;
+        self.pressure = 0.0;
+        self.parameter = None;
+    }
+
+    /// Apply a value of the timbre controller, or of a controller that selects, sets or steps a
+    /// parameter. Returns what it changed, if anything.
+    pub fn controller(&mut self, control: u8, value: u8) -> Option<Change> {
+        match control {
+            RPN_COARSE => {
+                let fine = self.parameter.map_or(RPN_NULL.1, |(_, fine)| fine);
+                self.select((value, fine));
+            }
+            RPN_FINE => {
+                let coarse = self.parameter.map_or(RPN_NULL.0, |(coarse, _)| coarse);
+                self.select((coarse, value));
+            }
+            // parameters other than the registered ones aren't supported
+            NRPN_COARSE | NRPN_FINE => self.parameter = None,
+            DATA_ENTRY if self.parameter == Some(PITCH_BEND_SENSITIVITY) => {
+                self.set_bend_range(value as f64);
+                return Some(Change::BendRange);
+            }
+            DATA_ENTRY_FINE if self.parameter == Some(PITCH_BEND_SENSITIVITY) => {
+                self.cents = value as f64;
+                return Some(Change::BendRange);
+            }
+            // the value of an increment or decrement is ignored, as the specification allows
+            DATA_INCREMENT if self.parameter == Some(PITCH_BEND_SENSITIVITY) => {
+                self.step_bend_range(1.0);
+                return Some(Change::BendRange);
+            }
+            DATA_DECREMENT if self.parameter == Some(PITCH_BEND_SENSITIVITY) => {
+                self.step_bend_range(-1.0);
+                return Some(Change::BendRange);
+            }
+            DATA_ENTRY if self.parameter == Some(MPE_CONFIGURATION) => {
+                return Some(Change::Zone(value));
+            }
+            TIMBRE => {
+                self.timbre = value as f64 / 127.0;
+                return Some(Change::Timbre);
+            }
+            _ => {}
+        }
+        None
+    }
+
+    /// Raise the bend range by `cents`, or lower it by negative `cents` as far as no bend at all.
+    fn step_bend_range(&mut self, cents: f64) {
+        let range = (self.bend_range() * 100.0 + cents).max(0.0);
+        self.semitones = (range / 100.0).floor();
+        self.cents = range - self.semitones * 100.0;
+    }
+
+    /// Select `parameter` for data entry, or deselect it if it is the null parameter.
+    fn select(&mut self, parameter: (u8, u8)) {
+        self.parameter = (parameter != RPN_NULL).then_some(parameter);
+    }
+}
+
+/// (From fundsp/examples/live_adsr.rs)
+/// Algorithm is from here: https://sites.uci.edu/camp2014/2014/04/30/managing-midi-pitchbend-messages/
+/// Converts a MIDI pitch-bend message to a factor that bends the pitch by up to `range` semitones
+/// either way.
+fn pitch_bend_factor(bend: u16, range: f64) -> f64 {
+    let bend = (bend as f64 - BEND_CENTRE as f64) / BEND_CENTRE as f64;
+    2.0_f64.powf(range * bend / 12.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Select registered parameter `(coarse, fine)` on `channel`.
+    fn select(channel: &mut ChannelState, (coarse, fine): (u8, u8)) {
+        assert_eq!(channel.controller(RPN_COARSE, coarse), None);
+        assert_eq!(channel.controller(RPN_FINE, fine), None);
+    }
+
+    #[test]
+    fn rpn_0_sets_and_steps_the_bend_range() {
+        let mut channel = ChannelState::new(DEFAULT_BEND_RANGE);
+        select(&mut channel, PITCH_BEND_SENSITIVITY);
+        assert_eq!(channel.controller(DATA_ENTRY, 12), Some(Change::BendRange));
+        assert_eq!(channel.bend_range(), 12.0);
+        assert_eq!(
+            channel.controller(DATA_ENTRY_FINE, 50),
+            Some(Change::BendRange)
+        );
+        assert_eq!(channel.bend_range(), 12.5);
+
+        // increments and decrements step by a cent, carrying into the semitones
+        for _ in 0..51 {
+            channel.controller(DATA_INCREMENT, 0);
+        }
+        assert_eq!(channel.bend_range(), 13.01);
+        assert_eq!(
+            channel.controller(DATA_DECREMENT, 0),
+            Some(Change::BendRange)
+        );
+        assert_eq!(
+            channel.controller(DATA_DECREMENT, 0),
+            Some(Change::BendRange)
+        );
+        assert_eq!(channel.bend_range(), 12.99);
+
+        // coarse data entry starts the cents again, and decrements stop at no bend
+        channel.controller(DATA_ENTRY, 0);
+        assert_eq!(channel.bend_range(), 0.0);
+        channel.controller(DATA_DECREMENT, 0);
+        assert_eq!(channel.bend_range(), 0.0);
+    }
+
+    #[test]
+    fn null_and_other_parameters_leave_the_bend_range_alone() {
+        let mut channel = ChannelState::new(DEFAULT_BEND_RANGE);
+        // no parameter is selected to begin with
+        assert_eq!(channel.controller(DATA_ENTRY, 12), None);
+
+        select(&mut channel, PITCH_BEND_SENSITIVITY);
+        select(&mut channel, RPN_NULL);
+        for control in [DATA_ENTRY, DATA_ENTRY_FINE, DATA_INCREMENT, DATA_DECREMENT] {
+            assert_eq!(channel.controller(control, 12), None);
+        }
+
+        // a non-registered parameter deselects the registered one
+        select(&mut channel, PITCH_BEND_SENSITIVITY);
+        channel.controller(NRPN_COARSE, 0);
+        assert_eq!(channel.controller(DATA_ENTRY, 12), None);
+
+        // and so does Reset All Controllers
+        select(&mut channel, PITCH_BEND_SENSITIVITY);
+        channel.reset();
+        assert_eq!(channel.controller(DATA_ENTRY, 12), None);
+        assert_eq!(channel.bend_range(), DEFAULT_BEND_RANGE);
+
+        // the MPE Configuration Message only reports the zone
+        select(&mut channel, MPE_CONFIGURATION);
+        assert_eq!(channel.controller(DATA_ENTRY, 7), Some(Change::Zone(7)));
+        assert_eq!(channel.bend_range(), DEFAULT_BEND_RANGE);
+    }
+
+    #[test]
+    fn bend_spans_the_range_in_semitones_and_cents() {
+        let mut channel = ChannelState::new(DEFAULT_BEND_RANGE);
+        select(&mut channel, PITCH_BEND_SENSITIVITY);
+        channel.controller(DATA_ENTRY, 7);
+        channel.controller(DATA_ENTRY_FINE, 25);
+
+        // the wheel fully down (-8192), centred (0) and fully up (8191)
+        let mut semitones = |bend: u16| {
+            channel.pitch_bend(bend);
+            12.0 * channel.bend_factor().log2()
+        };
+        assert!((semitones(0) + 7.25).abs() < 1e-12);
+        assert_eq!(semitones(BEND_CENTRE), 0.0);
+        assert!((semitones(16383) - 7.25 * 8191.0 / 8192.0).abs() < 1e-12);
+
+        for range in [0.0, 2.0, 48.0] {
+            assert_eq!(pitch_bend_factor(0, range), 2.0_f64.powf(-range / 12.0));
+            assert_eq!(pitch_bend_factor(BEND_CENTRE, range), 1.0);
+            assert!(pitch_bend_factor(16383, range) <= 2.0_f64.powf(range / 12.0));
+        }
+    }
+}
@@ -0,0 +1,196 @@
+//! Command-line interface.
+//!
+//! Every setting that used to be a static in main.rs is an option here, so the synth can be
+//! reconfigured without recompiling. The audio, MIDI and string options are global: they can be
+//! given before or after the subcommand.
+
+use crate::bidirectional::Waveguide;
+use crate::body::{BodyPreset, ImpulseResponse};
+use crate::channel::{DEFAULT_BEND_RANGE, DEFAULT_PLUCK_POSITION};
+use crate::excitation::Excitation;
+use crate::expression::Aftertouch;
+use crate::loss::LossFilter;
+use crate::pickup::{PickupPosition, PickupType};
+use crate::render::BitDepth;
+use crate::variant::Variant;
+use crate::voice::StealPolicy;
+use crate::waveguide::Interpolation;
+use clap::{Args, Parser, Subcommand};
+use std::path::PathBuf;
+
+/// Plucked string synthesis with a Karplus-Strong waveguide, played live from MIDI or from files.
+#[derive(Debug, Parser)]
+#[command(name = "twang", version)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    #[command(flatten)]
+    pub audio: AudioArgs,
+    #[command(flatten)]
+    pub midi: MidiArgs,
+    #[command(flatten)]
+    pub strings: StringArgs,
+    #[command(flatten)]
+    pub sound: SoundArgs,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Play live from MIDI input (the default), or play a MIDI file or note list.
+    Play {
+        /// Standard MIDI File (.mid) or note list to play instead of live input.
+        file: Option<PathBuf>,
+    },
+    /// Render a MIDI file or note list to a WAV file, without any audio or MIDI device.
+    Render {
+        /// Standard MIDI File (.mid) or note list to render.
+        input: PathBuf,
+        /// WAV file to write.
+        output: PathBuf,
+        /// Bits per sample: 16, 24, or 32 for floating-point samples.
+        #[arg(long, default_value = "16")]
+        bit_depth: BitDepth,
+    },
+    /// List the audio hosts, their output devices and configurations, and the MIDI input ports.
+    Devices,
+    /// Print the tuning of every string: note, frequency, tension and waveguide length.
+    Tune {
+        /// Also play every note the instrument can reach through the synth, offline, and measure
+        /// how far its pitch is from equal temperament.
+        #[arg(long)]
+        measure: bool,
+    },
+}
+
+#[derive(Clone, Debug, Args)]
+#[command(next_help_heading = "Audio")]
+pub struct AudioArgs {
+    /// Audio API to play through, e.g. ALSA or JACK [default: the platform default].
+    #[arg(long, global = true)]
+    pub host: Option<String>,
+    /// Audio output device, or part of its name [default: the system default].
+    #[arg(long, global = true)]
+    pub device: Option<String>,
+    /// Sample rate (Hz) [default: the device's rate, or 44100 when rendering].
+    #[arg(long, global = true)]
+    pub sample_rate: Option<u32>,
+    /// Audio buffer size (frames) [default: chosen by the device].
+    #[arg(long, global = true)]
+    pub buffer_size: Option<u32>,
+}
+
+#[derive(Debug, Args)]
+#[command(next_help_heading = "MIDI")]
+pub struct MidiArgs {
+    /// MIDI input: a port index, part of a port name, `all` to merge every port, or `none` to only
+    /// use the virtual port [default: ask when there are several].
+    #[arg(long, global = true)]
+    pub midi_port: Option<String>,
+    /// Create a virtual MIDI input with this name for other applications to send to (Linux and
+    /// macOS only).
+    #[arg(long, global = true)]
+    pub virtual_port: Option<String>,
+}
+
+#[derive(Debug, Args)]
+#[command(next_help_heading = "Strings")]
+pub struct StringArgs {
+    /// Instrument preset (guitar, bass, mandolin, harp), or `none` (the default) for a pool of
+    /// identical strings that can each play any note. A preset only plays the notes its strings
+    /// can reach.
+    #[arg(long, global = true, default_value = "none")]
+    pub preset: String,
+    /// Number of strings in the pool when there is no preset.
+    #[arg(long, global = true, default_value_t = 8)]
+    pub voices: usize,
+    /// Which string is re-plucked when all of them are busy.
+    #[arg(long, global = true, value_enum, default_value_t = StealPolicy::Oldest)]
+    pub steal: StealPolicy,
+    /// What channel and polyphonic aftertouch do to the strings being pressed.
+    #[arg(long, global = true, value_enum, default_value_t = Aftertouch::Vibrato)]
+    pub aftertouch: Aftertouch,
+    /// How far the pitch bend wheel bends the pitch either way (semitones), until RPN 0 changes it.
+    #[arg(long, global = true, default_value_t = DEFAULT_BEND_RANGE)]
+    pub bend_range: f64,
+    /// Play in MPE mode from the start, with every channel but the first in the lower zone, for
+    /// controllers that don't send an MPE Configuration Message.
+    #[arg(long, global = true)]
+    pub mpe: bool,
+    /// Open note of the pool strings (MIDI note number).
+    #[arg(long, global = true, default_value_t = 59)]
+    pub open_note: u8,
+    /// Linear density of the pool strings (kg/m).
+    #[arg(long, global = true, default_value_t = 0.000477)]
+    pub density: f64,
+    /// Scale length of the pool strings (m).
+    #[arg(long, global = true, default_value_t = 0.64)]
+    pub length: f64,
+    /// Time for the fundamental of every string to die away by 60 dB (s) [default: 4].
+    #[arg(long, global = true)]
+    pub decay_time: Option<f64>,
+    /// Time for the partials of every string around 4 kHz to die away by 60 dB (s). Shapes the
+    /// loss filter instead of `--brightness`.
+    #[arg(long, global = true)]
+    pub high_decay_time: Option<f64>,
+    /// Gain of the highest partials on each round trip of every string, relative to the
+    /// fundamental (0 to 1) [default: 0.5].
+    #[arg(long, global = true)]
+    pub brightness: Option<f64>,
+    /// Where notes are plucked, as a fraction of the string length from the bridge (up to 0.5),
+    /// until changed by MIDI CC 70 or a note list.
+    #[arg(long, global = true, default_value_t = DEFAULT_PLUCK_POSITION)]
+    pub pluck_position: f64,
+    /// Diameter of the core of every string (m), which sets how stiff it is along with
+    /// `--youngs-modulus`. The pool strings have no stiffness unless this is given.
+    #[arg(long, global = true)]
+    pub diameter: Option<f64>,
+    /// Young's modulus of the core of every string (Pa) [default: 2e11, steel].
+    #[arg(long, global = true)]
+    pub youngs_modulus: Option<f64>,
+}
+
+#[derive(Clone, Debug, Args)]
+#[command(next_help_heading = "Sound")]
+pub struct SoundArgs {
+    /// How the waveguide delay reads between samples, which sets how accurately it is tuned.
+    #[arg(long, global = true, value_enum, default_value_t = Interpolation::Lagrange3)]
+    pub interpolation: Interpolation,
+    /// The Karplus-Strong algorithm each string is played with.
+    #[arg(long, global = true, value_enum, default_value_t = Variant::Pluck)]
+    pub variant: Variant,
+    /// Chance that the drum variant keeps the sign of the loop on each sample (0 to 1). 0.5 is the
+    /// classic drum; higher values keep more of the pitch.
+    #[arg(long, global = true, default_value_t = 0.5)]
+    pub drum_blend: f64,
+    /// How hard the bowed variant presses the bow into the string (0 to 1).
+    #[arg(long, global = true, default_value_t = 0.5)]
+    pub bow_pressure: f64,
+    /// The waveguide each string is built from.
+    #[arg(long, global = true, value_enum, default_value_t = Waveguide::Single)]
+    pub waveguide: Waveguide,
+    /// Lowpass in the feedback loop that makes the high partials die away sooner than the low
+    /// ones.
+    #[arg(long, global = true, value_enum, default_value_t = LossFilter::OnePole)]
+    pub loss_filter: LossFilter,
+    /// How the strings are set vibrating at the start of every note.
+    #[arg(long, global = true, value_enum, default_value_t = Excitation::Pick)]
+    pub excitation: Excitation,
+    /// Hear the strings through an electric-guitar pickup at this position, instead of
+    /// acoustically.
+    #[arg(long, global = true, value_enum)]
+    pub pickup: Option<PickupPosition>,
+    /// The kind of pickup used with `--pickup`.
+    #[arg(long, global = true, value_enum, default_value_t = PickupType::SingleCoil)]
+    pub pickup_type: PickupType,
+    /// How strongly every string resonates with the others through the bridge, from 0 (not at
+    /// all) to 1.
+    #[arg(long, global = true, default_value_t = 0.0)]
+    pub coupling: f64,
+    /// Hear the strings through the resonances of an instrument body.
+    #[arg(long, global = true, value_enum)]
+    pub body: Option<BodyPreset>,
+    /// Hear the strings through the body whose impulse response is in this WAV file, instead of
+    /// a `--body` preset.
+    #[arg(long, global = true, value_parser = ImpulseResponse::parse, conflicts_with = "body")]
+    pub body_ir: Option<ImpulseResponse>,
+}
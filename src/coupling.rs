@@ -0,0 +1,172 @@
+//! Sympathetic resonance between the strings of an instrument.
+//!
+//! The strings of a real instrument all stand on the same bridge, so every string that is left
+//! ringing is gently driven by the others, and picks up whichever of their partials match its
+//! own. Each voice writes the output of its string to its `VoiceControls::bridge`, and
+//! `BridgeDrive` feeds the average of the other strings back into its waveguide, alongside the
+//! excitation.
+//!
+//! At its fundamental, a loop with gain `g` amplifies whatever it is driven with by `1 / (1 - g)`,
+//! which for a long-ringing string is in the hundreds. The drive is scaled by `1 - g` to cancel
+//! that, so a string picks up at most `coupling` times the motion of the bridge and two strings in
+//! unison can never drive each other into runaway feedback.
+
+use crate::string::StringModel;
+use fundsp::hacker::*;
+
+/// Feeds the average output of the other strings on the bridge into a string.
+#[derive(Clone)]
+pub struct BridgeDrive {
+    others: Vec<Shared<f64>>,
+    coupling: f64,
+    string: StringModel,
+    // fundamental the drive is scaled for, and the resulting gain
+    freq: f64,
+    gain: f64,
+}
+
+impl AudioNode for BridgeDrive {
+    const ID: u64 = 1012;
+    type Sample = f64;
+    type Inputs = U1;
+    type Outputs = U1;
+    type Setting = ();
+
+    fn reset(&mut self, _sample_rate: Option<f64>) {
+        // rescale on the next sample
+        self.freq = 0.0;
+    }
+
+    #[inline]
+    fn tick(&mut self, input: &Frame<f64, U1>) -> Frame<f64, U1> {
+        if self.others.is_empty() || self.coupling == 0.0 {
+            return [0.0].into();
+        }
+        if input[0] != self.freq {
+            self.freq = input[0];
+            self.gain =
+                self.coupling * (1.0 - self.string.loop_gain(self.freq)) / self.others.len() as f64;
+        }
+        let bridge: f64 = self.others.iter().map(|other| other.value()).sum();
+        [bridge * self.gain].into()
+    }
+}
+
+/// Drive `string` with the average of the `others` on the bridge, scaled by `coupling` (0 to 1)
+/// and by the loss of the string at its fundamental.
+/// - Input 0: fundamental (Hz)
+/// - Output 0: drive to add to the excitation
+pub fn bridge_drive(
+    others: Vec<Shared<f64>>,
+    coupling: f64,
+    string: &StringModel,
+) -> An<BridgeDrive> {
+    An(BridgeDrive {
+        others,
+        coupling: clamp(0.0, 1.0, coupling),
+        string: *string,
+        freq: 0.0,
+        gain: 0.0,
+    })
+}
+
+/// Passes the string through while writing it to its place on the bridge.
+#[derive(Clone)]
+pub struct BridgeTap {
+    bridge: Shared<f64>,
+}
+
+impl AudioNode for BridgeTap {
+    const ID: u64 = 1013;
+    type Sample = f64;
+    type Inputs = U1;
+    type Outputs = U1;
+    type Setting = ();
+
+    fn reset(&mut self, _sample_rate: Option<f64>) {
+        self.bridge.set_value(0.0);
+    }
+
+    #[inline]
+    fn tick(&mut self, input: &Frame<f64, U1>) -> Frame<f64, U1> {
+        self.bridge.set_value(input[0]);
+        [input[0]].into()
+    }
+}
+
+/// Write the string to `bridge`, for the other strings to resonate with.
+/// - Input 0: string
+/// - Output 0: string, unchanged
+pub fn bridge_tap(bridge: &Shared<f64>) -> An<BridgeTap> {
+    An(BridgeTap {
+        bridge: bridge.clone(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::loss::{loop_filter, LossFilter};
+    use crate::peak;
+
+    // Two loops of `lengths` samples on one bridge, the first plucked with noise and the second
+    // left at rest, each driven by the other. Returns the output of each.
+    fn coupled(lengths: [usize; 2], coupling: f64) -> [Vec<f64>; 2] {
+        let sample_rate = 44100.0;
+        let bridge = [Shared::new(0.0), Shared::new(0.0)];
+        let mut outputs = [vec![], vec![]];
+        let mut strings: Vec<_> = (0..2)
+            .map(|i| {
+                let freq = sample_rate / lengths[i] as f64;
+                let string = StringModel::tuned(freq, 0.000477, 0.64);
+                let mut filter = loop_filter(LossFilter::OnePole, &string);
+                let mut drive = bridge_drive(vec![bridge[1 - i].clone()], coupling, &string);
+                let mut tap = bridge_tap(&bridge[i]);
+                filter.reset(Some(sample_rate));
+                drive.reset(Some(sample_rate));
+                tap.reset(Some(sample_rate));
+                (freq, filter, drive, tap)
+            })
+            .collect();
+        for n in 0..sample_rate as usize {
+            for (i, (freq, filter, drive, tap)) in strings.iter_mut().enumerate() {
+                let pluck = if i == 0 && n < lengths[0] {
+                    rnd(n as i64) - 0.5
+                } else {
+                    0.0
+                };
+                let looped = match n.checked_sub(lengths[i]) {
+                    Some(m) => filter.tick(&[outputs[i][m], *freq].into())[0],
+                    None => 0.0,
+                };
+                let drive = drive.tick(&[*freq].into())[0];
+                outputs[i].push(tap.tick(&[looped + pluck + drive].into())[0]);
+            }
+        }
+        outputs
+    }
+
+    // Amplitude of `samples` at the fundamental of a loop of `length` samples, at 44.1 kHz.
+    fn fundamental(samples: &[f64], length: usize) -> f64 {
+        crate::amplitude(samples, 44100.0 / length as f64, 44100.0)
+    }
+
+    #[test]
+    fn strings_in_unison_ring_in_sympathy() {
+        let [plucked, sympathetic] = coupled([200, 200], 0.5);
+        // the string at rest picks up the plucked one without ever outgrowing it, and dies away
+        // with it
+        let picked_up = peak(&sympathetic[22050..]);
+        assert!(picked_up > 0.01 * peak(&plucked[22050..]));
+        assert!(picked_up < peak(&plucked[..22050]));
+        assert!(peak(&sympathetic[33075..]) < peak(&sympathetic[..33075]));
+
+        // a string out of tune with it hardly rings at its own fundamental, and an uncoupled
+        // string doesn't move at all
+        let [_, detuned] = coupled([200, 283], 0.5);
+        let ringing = fundamental(&sympathetic[22050..], 200);
+        assert!(fundamental(&detuned[22050..], 283) < 0.05 * ringing);
+        let [_, uncoupled] = coupled([200, 200], 0.0);
+        assert!(uncoupled.iter().all(|&x| x == 0.0));
+    }
+}
@@ -0,0 +1,223 @@
+//! Dispersion filter for stiff strings.
+//!
+//! The higher partials of a stiff string travel faster than its fundamental, so they come round
+//! the loop sooner and sound sharp of the harmonic series: partial `n` of a string with
+//! inharmonicity `B` sounds at `n * sqrt((1 + B n^2) / (1 + B))` times its fundamental. A delay
+//! line delays every frequency alike, so `Dispersion` adds a cascade of identical first-order
+//! allpass filters to the loop, whose delay falls with frequency. Their coefficient is fitted so a
+//! reference partial lands exactly where the string's stiffness puts it, and the partials below it
+//! follow to within a cent or two. The cascade also delays the fundamental, which `design()`
+//! reports so the waveguide can be shortened to stay in tune.
+
+use crate::string::StringModel;
+use fundsp::hacker::*;
+
+// Number of allpass stages. More stages follow the stretch of a stiff string more closely, but
+// take up more of the loop.
+const STAGES: usize = 8;
+
+// Partial the cascade is fitted to, unless it would be too close to the Nyquist frequency.
+static REFERENCE_PARTIAL: f64 = 8.0;
+
+// Largest share of the loop the cascade may delay the fundamental by. The very stiff strings of
+// high notes are stretched less than they should be rather than leaving no room for the delay
+// line.
+static MAX_LOOP_SHARE: f64 = 0.5;
+
+// Steps of the bisection that fits the coefficient, each halving the range it can lie in.
+static FIT_STEPS: usize = 40;
+
+/// Coefficient of every allpass stage of a `Dispersion`, and the delay of the whole cascade at the
+/// fundamental (samples), for one note.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DispersionCoefficients {
+    pub coefficient: f64,
+    pub delay: f64,
+}
+
+/// Fit the allpass cascade to `string` playing at `freq_hz`. A string without stiffness, or a note
+/// too high to have a reference partial well below the Nyquist frequency, gets a coefficient of 0,
+/// which bypasses the cascade.
+pub fn design(string: &StringModel, freq_hz: f64, sample_rate: f64) -> DispersionCoefficients {
+    let bypass = DispersionCoefficients {
+        coefficient: 0.0,
+        delay: 0.0,
+    };
+    let b = string.stiffness_at(freq_hz);
+    let partial = min(REFERENCE_PARTIAL, (0.4 * sample_rate / freq_hz).floor());
+    if b <= 0.0 || partial < 2.0 {
+        return bypass;
+    }
+
+    let omega = TAU * freq_hz / sample_rate;
+    let target = omega * string.partial_ratio(partial, freq_hz);
+    let period = TAU / omega;
+    let stages = STAGES as f64;
+    let delay = |a: f64| stages * phase(a, omega) / omega;
+    // with the rest of the loop making up one period at the fundamental, the phase by which the
+    // reference partial falls short of `partial` whole cycles per trip; it shrinks as the
+    // coefficient goes from 0 towards -1 and the cascade disperses more
+    let shortfall =
+        |a: f64| target * (period - delay(a)) + stages * phase(a, target) - partial * TAU;
+
+    let (mut low, mut high) = (-1.0, 0.0);
+    for _ in 0..FIT_STEPS {
+        let a = 0.5 * (low + high);
+        if shortfall(a) > 0.0 && delay(a) < MAX_LOOP_SHARE * period {
+            high = a;
+        } else {
+            low = a;
+        }
+    }
+    if high == 0.0 {
+        return bypass;
+    }
+    DispersionCoefficients {
+        coefficient: high,
+        delay: delay(high),
+    }
+}
+
+/// Phase lag (radians) of the allpass `(a + z^-1) / (1 + a z^-1)` at `omega` (radians per sample).
+fn phase(a: f64, omega: f64) -> f64 {
+    omega.sin().atan2(a + omega.cos()) - (a * omega.sin()).atan2(1.0 + a * omega.cos())
+}
+
+/// A cascade of first-order allpass filters that stretches the partials of the loop, redesigned
+/// by `design()` whenever the fundamental changes.
+#[derive(Clone)]
+pub struct Dispersion {
+    string: StringModel,
+    sample_rate: f64,
+    freq: f64,
+    coefficient: f64,
+    // previous input and output of each stage
+    x1: [f64; STAGES],
+    y1: [f64; STAGES],
+}
+
+impl AudioNode for Dispersion {
+    const ID: u64 = 1008;
+    type Sample = f64;
+    type Inputs = U2;
+    type Outputs = U1;
+    type Setting = ();
+
+    fn reset(&mut self, sample_rate: Option<f64>) {
+        if let Some(sample_rate) = sample_rate {
+            self.sample_rate = sample_rate;
+            // redesign on the next sample
+            self.freq = 0.0;
+        }
+        self.x1 = [0.0; STAGES];
+        self.y1 = [0.0; STAGES];
+    }
+
+    #[inline]
+    fn tick(&mut self, input: &Frame<f64, U2>) -> Frame<f64, U1> {
+        if input[1] != self.freq {
+            self.freq = input[1];
+            self.coefficient = design(&self.string, self.freq, self.sample_rate).coefficient;
+        }
+        if self.coefficient == 0.0 {
+            return [input[0]].into();
+        }
+        let a = self.coefficient;
+        let mut x = input[0];
+        for (x1, y1) in self.x1.iter_mut().zip(self.y1.iter_mut()) {
+            let y = a * x + *x1 - a * *y1;
+            *x1 = x;
+            *y1 = y;
+            x = y;
+        }
+        [x].into()
+    }
+}
+
+/// Dispersion for the feedback path of `string`, fitted to its stiffness at the fundamental it is
+/// playing.
+/// - Input 0: audio
+/// - Input 1: fundamental (Hz)
+/// - Output 0: dispersed audio
+pub fn dispersion(string: &StringModel) -> An<Dispersion> {
+    let mut node = Dispersion {
+        string: *string,
+        sample_rate: DEFAULT_SR,
+        freq: 0.0,
+        coefficient: 0.0,
+        x1: [0.0; STAGES],
+        y1: [0.0; STAGES],
+    };
+    node.reset(Some(DEFAULT_SR));
+    An(node)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::measure_frequency;
+    use std::f64::consts::PI;
+
+    // A bass piano string: 1.5 mm of plain steel, 1 m long, tuned to A2. Its B is about 7e-4.
+    fn piano_string() -> StringModel {
+        let diameter = 0.0015;
+        let linear_density = 7850.0 * PI * diameter * diameter / 4.0;
+        StringModel {
+            diameter,
+            ..StringModel::tuned(110.0, linear_density, 1.0)
+        }
+    }
+
+    // Rings an impulse around a loop of a whole-sample delay line and the dispersion filter,
+    // tuned to `freq_hz`, for `length` samples.
+    fn ring(string: &StringModel, freq_hz: f64, sample_rate: f64, length: usize) -> Vec<f64> {
+        let mut filter = dispersion(string).0;
+        filter.reset(Some(sample_rate));
+        let delay = design(string, freq_hz, sample_rate).delay;
+        let mut line = vec![0.0; (sample_rate / freq_hz - delay).round() as usize];
+        let mut output = Vec::with_capacity(length);
+        for i in 0..length {
+            let index = i % line.len();
+            let sample = filter.tick(&[line[index], freq_hz].into())[0];
+            line[index] = 0.999 * sample + if i == 0 { 1.0 } else { 0.0 };
+            output.push(sample);
+        }
+        output
+    }
+
+    #[test]
+    fn partials_follow_the_stiff_string() {
+        let string = piano_string();
+        let sample_rate = 44100.0;
+        let samples = ring(&string, 110.0, sample_rate, sample_rate as usize / 2);
+        let fundamental = measure_frequency(&samples, 110.0, sample_rate);
+        for n in 2..=8 {
+            let expected = fundamental * string.partial_ratio(n as f64, 110.0);
+            let measured = measure_frequency(&samples, expected, sample_rate);
+            let cents = 1200.0 * (measured / expected).log2();
+            assert!(
+                cents.abs() < 3.0,
+                "partial {n} is {cents:+.2} cents from the stiff string"
+            );
+        }
+
+        // the stretch is well beyond the error
+        let eighth = measure_frequency(
+            &samples,
+            fundamental * string.partial_ratio(8.0, 110.0),
+            sample_rate,
+        );
+        let stretch = 1200.0 * (eighth / (8.0 * fundamental)).log2();
+        assert!(
+            stretch > 30.0,
+            "partial 8 is only {stretch:+.2} cents sharp"
+        );
+    }
+
+    #[test]
+    fn flexible_strings_bypass_the_cascade() {
+        let string = StringModel::tuned(110.0, 0.01, 1.0);
+        assert_eq!(design(&string, 110.0, 44100.0).coefficient, 0.0);
+        assert_eq!(design(&string, 110.0, 44100.0).delay, 0.0);
+    }
+}
@@ -0,0 +1,64 @@
+//! Errors from the audio and MIDI devices.
+//!
+//! Device setup can fail in many ways that the user can fix (a missing device, an unsupported
+//! sample rate, a port that disappeared), so these are reported as an `Error` and returned to
+//! `main()` rather than panicking on the audio or MIDI thread.
+
+use cpal::SampleFormat;
+use midir::ConnectErrorKind;
+
+/// Something went wrong opening or running an audio or MIDI device.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Audio host '{name}' is not available, expected one of {available:?}")]
+    UnknownHost {
+        name: String,
+        available: Vec<&'static str>,
+    },
+    #[error("No audio output device matching '{0}'")]
+    NoMatchingDevice(String),
+    #[error("No default audio output device")]
+    NoDefaultDevice,
+    #[error("The audio device does not support a sample rate of {0} Hz")]
+    UnsupportedSampleRate(u32),
+    #[error("Buffer size of {frames} frames is outside the device's range ({min}-{max})")]
+    UnsupportedBufferSize { frames: u32, min: u32, max: u32 },
+    #[error("The audio device uses the unsupported sample format {0}")]
+    UnsupportedFormat(SampleFormat),
+    #[error("The audio thread stopped before the stream started")]
+    AudioThreadStopped,
+    #[error(transparent)]
+    HostUnavailable(#[from] cpal::HostUnavailable),
+    #[error(transparent)]
+    Devices(#[from] cpal::DevicesError),
+    #[error(transparent)]
+    DeviceName(#[from] cpal::DeviceNameError),
+    #[error(transparent)]
+    DefaultConfig(#[from] cpal::DefaultStreamConfigError),
+    #[error(transparent)]
+    SupportedConfigs(#[from] cpal::SupportedStreamConfigsError),
+    #[error(transparent)]
+    BuildStream(#[from] cpal::BuildStreamError),
+    #[error(transparent)]
+    PlayStream(#[from] cpal::PlayStreamError),
+
+    #[error(transparent)]
+    MidiInit(#[from] midir::InitError),
+    #[error("No MIDI input port {index}, there are {count} ports")]
+    NoMidiPort { index: usize, count: usize },
+    #[error("No MIDI input port matching '{name}' in {available:?}")]
+    NoMatchingMidiPort {
+        name: String,
+        available: Vec<String>,
+    },
+    #[error("Failed to connect to MIDI input '{port}': {kind}")]
+    MidiConnect {
+        port: String,
+        kind: ConnectErrorKind,
+    },
+    #[cfg(not(unix))]
+    #[error("Virtual MIDI input '{0}' is not supported on this platform")]
+    VirtualPortUnsupported(String),
+    #[error("No MIDI devices attached")]
+    NoMidiInput,
+}
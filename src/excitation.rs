@@ -0,0 +1,316 @@
+//! Excitation of the waveguide loop.
+//!
+//! A Karplus-Strong string is plucked by filling its delay loop with an initial waveform, whose
+//! shape sets the character of the attack. `Exciter` writes one such burst into the loop at the
+//! start of every note. Harder notes are both louder and brighter: the velocity sets the level
+//! of the burst and opens up the lowpass that shapes it.
+//!
+//! One trip around the loop covers the string twice, out and back, so the pick and finger shapes
+//! are laid out as the displacement of the string followed by its inverted reflection. This
+//! leaves no DC in the loop, which would otherwise never die away.
+//!
+//! Where the string is plucked matters too: the partials with a node at the pluck position are
+//! not excited at all. The pick and finger triangles have their corner at the pluck position, and
+//! the noise and hammer bursts are passed through the matching comb filter, subtracting a copy
+//! delayed by the time the wave takes to reflect off the nearer end of the string and come back.
+//! A `bidirectional()` string reflects the burst off its ends itself, so it takes the noise and
+//! hammer bursts without the comb.
+
+use fundsp::hacker::*;
+use std::f64::consts::PI;
+
+/// Furthest pluck position from the bridge, as a fraction of the string length. Plucking beyond
+/// the middle of the string sounds the same as plucking the same distance from the other end.
+pub static MAX_PLUCK_POSITION: f64 = 0.5;
+
+// Closest pluck position to the bridge. At the bridge itself, the string wouldn't move.
+static MIN_PLUCK_POSITION: f64 = 0.01;
+
+// Contact time of the hammer at full velocity (s). Softer strikes stay in contact for up to twice
+// as long, which makes them duller.
+static HAMMER_CONTACT: f64 = 0.001;
+
+/// The waveform written into the loop at the start of a note.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Excitation {
+    /// A burst of lowpassed white noise, one loop long, as in the original Karplus-Strong
+    /// algorithm, comb filtered for the pluck position.
+    Noise,
+    /// A plectrum: the string is pulled into a triangle with its corner at the pluck position and
+    /// released, giving a bright attack.
+    Pick,
+    /// A fingertip: the same triangle, rounded off by a heavier lowpass for a soft attack.
+    Finger,
+    /// A hammer, as in a piano or hammered dulcimer: a short pulse whose length is the contact
+    /// time of the hammer, shorter (and brighter) for harder strikes, comb filtered for the
+    /// position it strikes.
+    Hammer,
+}
+
+impl Excitation {
+    /// Whether the burst is the displacement of the whole string, laid out around the loop (pick
+    /// and finger), rather than a signal driven into the string at the pluck position (noise and
+    /// hammer).
+    pub fn is_displacement(&self) -> bool {
+        matches!(self, Excitation::Pick | Excitation::Finger)
+    }
+
+    /// Pole of the one-pole lowpass applied to the burst at `velocity` (0 to 1). Higher poles
+    /// give darker bursts.
+    fn pole(&self, velocity: f64) -> f64 {
+        match self {
+            Excitation::Noise => 0.9 * (1.0 - velocity),
+            Excitation::Pick => 0.6 * (1.0 - velocity),
+            Excitation::Finger => 0.95 - 0.35 * velocity,
+            Excitation::Hammer => 0.0,
+        }
+    }
+}
+
+/// Triangle with its corner at `position`, rising from 0 at `x = 0` to 1 and back to 0 at `x = 1`.
+fn triangle(x: f64, position: f64) -> f64 {
+    if x < position {
+        x / position
+    } else {
+        (1.0 - x) / (1.0 - position)
+    }
+}
+
+/// Next sample of white noise between -1 and 1, from a linear congruential generator.
+#[inline]
+pub(crate) fn noise(seed: &mut u64) -> f64 {
+    *seed = seed
+        .wrapping_mul(6364136223846793005)
+        .wrapping_add(1442695040888963407);
+    (*seed >> 11) as f64 / (1_u64 << 53) as f64 * 2.0 - 1.0
+}
+
+/// Half-sine pulse `width` samples long, starting at sample 0.
+fn pulse(n: f64, width: f64) -> f64 {
+    if (0.0..width).contains(&n) {
+        (PI * n / width).sin()
+    } else {
+        0.0
+    }
+}
+
+/// Writes a burst into the waveguide loop whenever the gate rises.
+#[derive(Clone)]
+pub struct Exciter {
+    excitation: Excitation,
+    reflect: bool,
+    sample_rate: f64,
+    // gate on the previous sample
+    gate: f64,
+    // samples since the burst started, the length of the loop when it did, and of the burst
+    elapsed: f64,
+    period: f64,
+    length: f64,
+    // pluck position of the burst, and the delay (samples) of its reflection
+    position: f64,
+    reflection: f64,
+    level: f64,
+    pole: f64,
+    width: f64,
+    lowpass: f64,
+    // the noise generator, and a copy that replays the same noise for the reflection
+    seed: u64,
+    reflected_seed: u64,
+}
+
+impl Exciter {
+    /// Sample `elapsed` of the burst, before the lowpass.
+    #[inline]
+    fn burst(&mut self) -> f64 {
+        let n = self.elapsed;
+        let t = n / self.period;
+        match self.excitation {
+            _ if n >= self.length => 0.0,
+            Excitation::Noise if !self.reflect => noise(&mut self.seed),
+            Excitation::Hammer if !self.reflect => pulse(n, self.width),
+            // the burst travelling towards the nearer end comes back inverted
+            Excitation::Noise => {
+                let direct = if n < self.period {
+                    noise(&mut self.seed)
+                } else {
+                    0.0
+                };
+                let reflected = if (0.0..self.period).contains(&(n - self.reflection)) {
+                    noise(&mut self.reflected_seed)
+                } else {
+                    0.0
+                };
+                direct - reflected
+            }
+            Excitation::Pick | Excitation::Finger => {
+                if t < 0.5 {
+                    triangle(2.0 * t, self.position)
+                } else {
+                    -triangle(2.0 - 2.0 * t, self.position)
+                }
+            }
+            Excitation::Hammer => pulse(n, self.width) - pulse(n - self.reflection, self.width),
+        }
+    }
+}
+
+impl AudioNode for Exciter {
+    const ID: u64 = 1005;
+    type Sample = f64;
+    type Inputs = U4;
+    type Outputs = U1;
+    type Setting = ();
+
+    fn reset(&mut self, sample_rate: Option<f64>) {
+        if let Some(sample_rate) = sample_rate {
+            self.sample_rate = sample_rate;
+        }
+        self.gate = 0.0;
+        self.elapsed = self.length;
+        self.lowpass = 0.0;
+    }
+
+    #[inline]
+    fn tick(&mut self, input: &Frame<f64, U4>) -> Frame<f64, U1> {
+        if input[0] > 0.0 && self.gate <= 0.0 {
+            let velocity = clamp(0.0, 1.0, input[2]);
+            self.elapsed = 0.0;
+            self.period = self.sample_rate / max(input[1], 1.0);
+            self.level = velocity;
+            self.pole = self.excitation.pole(velocity);
+            self.width = HAMMER_CONTACT * (2.0 - velocity) * self.sample_rate;
+            self.position = clamp(MIN_PLUCK_POSITION, MAX_PLUCK_POSITION, input[3]);
+            self.reflection = (self.position * self.period).round();
+            self.reflected_seed = self.seed;
+            // without its reflection, the burst ends one delay sooner
+            let reflection = if self.reflect { self.reflection } else { 0.0 };
+            self.length = match self.excitation {
+                Excitation::Noise => reflection + self.period,
+                Excitation::Hammer => reflection + self.width,
+                Excitation::Pick | Excitation::Finger => self.period,
+            };
+        }
+        self.gate = input[0];
+        let burst = self.burst();
+        self.elapsed = min(self.elapsed + 1.0, self.length);
+        self.lowpass = (1.0 - self.pole) * burst + self.pole * self.lowpass;
+        [self.lowpass * self.level].into()
+    }
+}
+
+/// Excite the string with `excitation` on every rising edge of the gate. With `reflect`, the noise
+/// and hammer bursts include their reflection off the nearer end of the string, as a single
+/// waveguide loop needs.
+/// - Input 0: gate, as set by `VoiceControls::control` and `retrigger()`
+/// - Input 1: fundamental (Hz), which sets the length of the burst
+/// - Input 2: velocity (0 to 1), which sets its level and brightness
+/// - Input 3: pluck position, as a fraction of the string length from the bridge
+/// - Output 0: burst to feed into the loop
+pub fn exciter(excitation: Excitation, reflect: bool) -> An<Exciter> {
+    let mut node = Exciter {
+        excitation,
+        reflect,
+        sample_rate: DEFAULT_SR,
+        gate: 0.0,
+        elapsed: 0.0,
+        period: 0.0,
+        length: 0.0,
+        position: 0.0,
+        reflection: 0.0,
+        level: 0.0,
+        pole: 0.0,
+        width: 0.0,
+        lowpass: 0.0,
+        seed: 1,
+        reflected_seed: 1,
+    };
+    node.reset(Some(DEFAULT_SR));
+    An(node)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static EXCITATIONS: [Excitation; 4] = [
+        Excitation::Noise,
+        Excitation::Pick,
+        Excitation::Finger,
+        Excitation::Hammer,
+    ];
+
+    // The burst for a note plucked at `position`, on a loop of 100 samples at 44.1 kHz, followed
+    // by the tail of its lowpass.
+    fn burst(excitation: Excitation, reflect: bool, velocity: f64, position: f64) -> Vec<f64> {
+        let sample_rate = 44100.0;
+        let mut node = exciter(excitation, reflect);
+        node.reset(Some(sample_rate));
+        (0..2000)
+            .map(|_| node.tick(&[1.0, sample_rate / 100.0, velocity, position].into())[0])
+            .collect()
+    }
+
+    #[test]
+    fn bursts_are_bounded_and_leave_no_dc() {
+        for excitation in EXCITATIONS {
+            for reflect in [false, true] {
+                let samples = burst(excitation, reflect, 1.0, 0.2);
+                let peak = crate::peak(&samples);
+                // the noise and its inverted reflection can add up to twice the level
+                assert!(peak > 0.1 && peak <= 2.0, "{excitation:?} peaks at {peak}");
+                // the bursts with a reflection are balanced by it
+                if reflect || excitation.is_displacement() {
+                    let dc: f64 = samples.iter().sum();
+                    assert!(dc.abs() < 1e-9, "{excitation:?} leaves {dc} in the loop");
+                }
+                // softer notes are quieter, and a note without velocity is silent
+                let soft = burst(excitation, reflect, 0.3, 0.2);
+                let soft_peak = crate::peak(&soft);
+                assert!(soft_peak < peak, "{excitation:?} is as loud played softly");
+                assert!(burst(excitation, reflect, 0.0, 0.2)
+                    .iter()
+                    .all(|&x| x == 0.0));
+            }
+        }
+    }
+
+    #[test]
+    fn held_gate_excites_once() {
+        for excitation in EXCITATIONS {
+            let samples = burst(excitation, true, 1.0, 0.2);
+            assert!(
+                samples[1000..].iter().all(|x| x.abs() < 1e-9),
+                "{excitation:?} is still exciting the string"
+            );
+        }
+    }
+
+    // Amplitude of harmonic `k` of the loop once `samples` have been wrapped around it, as the
+    // loop of `period` samples sums them.
+    fn harmonic(samples: &[f64], period: usize, k: usize) -> f64 {
+        let (mut re, mut im) = (0.0, 0.0);
+        for (i, sample) in samples.iter().enumerate() {
+            let phase = TAU * (k * i) as f64 / period as f64;
+            re += sample * phase.cos();
+            im += sample * phase.sin();
+        }
+        (re * re + im * im).sqrt()
+    }
+
+    #[test]
+    fn pluck_position_notches_its_harmonics() {
+        // plucked halfway along, the string has a node at the pluck position for every even
+        // harmonic, and none of the odd ones
+        for excitation in EXCITATIONS {
+            let samples = burst(excitation, true, 1.0, 0.5);
+            let odd = harmonic(&samples, 100, 1) + harmonic(&samples, 100, 3);
+            for k in [2, 4, 6] {
+                let even = harmonic(&samples, 100, k);
+                assert!(
+                    even < 1e-9 * odd,
+                    "{excitation:?} excites harmonic {k} by {even} against {odd}"
+                );
+            }
+        }
+    }
+}
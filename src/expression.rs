@@ -0,0 +1,386 @@
+//! Expression from pitch bend, aftertouch and timbre.
+//!
+//! The bend wheel moves in steps, and a step straight into the waveguide delay would be heard as
+//! a zipper, so every voice glides its bend to each new value over `BEND_GLIDE_TIME`.
+//!
+//! Pressing harder into a key that is already down can either shake the pitch of its string, as a
+//! fretting hand does for vibrato, or lean on the string to damp it, as the side of a picking hand
+//! does for a palm mute. `--aftertouch` chooses which, and the `VoiceAllocator` writes the pressure
+//! to each voice's `vibrato` or `damping`.
+//!
+//! Vibrato retunes the whole string, and retuning a stiff string redesigns its loss filter and
+//! dispersion, so the vibrato only moves every `CONTROL_PERIOD` samples, in steps far too small to
+//! hear. Damping is a flat gain in the loop, which has no phase of its own and so leaves the string
+//! in tune.
+//!
+//! Timbre (CC 74) turns down the tone of a voice like the tone knob of a guitar, with a lowpass
+//! on its output that is fully open at the top of the controller.
+
+use fundsp::hacker::*;
+
+// Rate of the vibrato (Hz), and how far it bends the pitch either way at full pressure
+// (semitones).
+static VIBRATO_RATE: f64 = 5.5;
+static VIBRATO_DEPTH: f64 = 0.5;
+
+// Samples between updates of the vibrato and the bend glide.
+static CONTROL_PERIOD: usize = 64;
+
+// Time constant of the bend glide (s), and how close it must come to the bend before it lands on
+// it exactly.
+static BEND_GLIDE_TIME: f64 = 0.01;
+static BEND_GLIDE_SNAP: f64 = 1.0e-6;
+
+// Time for a fully damped string to die away by 60 dB, on top of its own decay (s).
+static DAMPED_DECAY_TIME: f64 = 0.1;
+
+// Cutoff of the tone at the bottom of the timbre controller (Hz). It opens by a factor of
+// `TONE_RANGE` to the top.
+static TONE_MIN_CUTOFF: f64 = 200.0;
+static TONE_RANGE: f64 = 100.0;
+
+/// What aftertouch does to the strings it presses on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Aftertouch {
+    /// Shake the pitch, more widely the harder the key is pressed.
+    Vibrato,
+    /// Damp the string, so it dies away sooner the harder the key is pressed.
+    Damping,
+}
+
+/// A vibrato that shakes the pitch by up to `VIBRATO_DEPTH` semitones at `VIBRATO_RATE`.
+#[derive(Clone)]
+pub struct Vibrato {
+    sample_rate: f64,
+    // phase of the vibrato (cycles), samples until the next update, and the current pitch factor
+    phase: f64,
+    countdown: usize,
+    factor: f64,
+}
+
+impl AudioNode for Vibrato {
+    const ID: u64 = 1016;
+    type Sample = f64;
+    type Inputs = U1;
+    type Outputs = U1;
+    type Setting = ();
+
+    fn reset(&mut self, sample_rate: Option<f64>) {
+        if let Some(sample_rate) = sample_rate {
+            self.sample_rate = sample_rate;
+        }
+        self.phase = 0.0;
+        self.countdown = 0;
+        self.factor = 1.0;
+    }
+
+    #[inline]
+    fn tick(&mut self, input: &Frame<f64, U1>) -> Frame<f64, U1> {
+        if self.countdown == 0 {
+            self.countdown = CONTROL_PERIOD;
+            let depth = clamp(0.0, 1.0, input[0]);
+            // without pressure the pitch is left exactly alone, so the string isn't retuned
+            self.factor = if depth > 0.0 {
+                (VIBRATO_DEPTH * depth * (TAU * self.phase).sin() / 12.0).exp2()
+            } else {
+                1.0
+            };
+        }
+        self.countdown -= 1;
+        self.phase += VIBRATO_RATE / self.sample_rate;
+        self.phase -= self.phase.floor();
+        [self.factor].into()
+    }
+}
+
+/// Vibrato for the pitch of a string.
+/// - Input 0: depth (0 to 1), as set by `VoiceControls::vibrato`
+/// - Output 0: factor to scale the fundamental by
+pub fn vibrato() -> An<Vibrato> {
+    let mut node = Vibrato {
+        sample_rate: DEFAULT_SR,
+        phase: 0.0,
+        countdown: 0,
+        factor: 1.0,
+    };
+    node.reset(Some(DEFAULT_SR));
+    An(node)
+}
+
+/// A flat gain in the loop that damps the string.
+#[derive(Clone)]
+pub struct Damper {
+    // fundamental and damping the gain was worked out for
+    freq: f64,
+    damping: f64,
+    gain: f64,
+}
+
+impl AudioNode for Damper {
+    const ID: u64 = 1017;
+    type Sample = f64;
+    type Inputs = U3;
+    type Outputs = U1;
+    type Setting = ();
+
+    fn reset(&mut self, _sample_rate: Option<f64>) {
+        self.freq = 0.0;
+        self.damping = 0.0;
+        self.gain = 1.0;
+    }
+
+    #[inline]
+    fn tick(&mut self, input: &Frame<f64, U3>) -> Frame<f64, U1> {
+        if input[1] != self.freq || input[2] != self.damping {
+            self.freq = input[1];
+            self.damping = input[2];
+            // the loss is applied once per trip around the loop, so it is spread over the trips
+            // the string makes in `DAMPED_DECAY_TIME`
+            let damping = clamp(0.0, 1.0, self.damping);
+            self.gain = 10.0_f64.powf(-3.0 * damping / (max(self.freq, 1.0) * DAMPED_DECAY_TIME));
+        }
+        [input[0] * self.gain].into()
+    }
+}
+
+/// Damp the loop of a string. Fully damped, the string dies away by 60 dB in `DAMPED_DECAY_TIME`,
+/// whatever its pitch.
+/// - Input 0: audio
+/// - Input 1: fundamental (Hz)
+/// - Input 2: damping (0 to 1), from `VoiceControls::damping` and the release of the note
+/// - Output 0: damped audio
+pub fn damper() -> An<Damper> {
+    An(Damper {
+        freq: 0.0,
+        damping: 0.0,
+        gain: 1.0,
+    })
+}
+
+/// Glides a pitch bend factor to each new value.
+#[derive(Clone)]
+pub struct BendGlide {
+    // fraction of the way to the bend covered each update, samples until the next update, and
+    // the current factor (0 until the first sample, which it starts at)
+    coefficient: f64,
+    countdown: usize,
+    factor: f64,
+}
+
+impl AudioNode for BendGlide {
+    const ID: u64 = 1019;
+    type Sample = f64;
+    type Inputs = U1;
+    type Outputs = U1;
+    type Setting = ();
+
+    fn reset(&mut self, sample_rate: Option<f64>) {
+        if let Some(sample_rate) = sample_rate {
+            let period = CONTROL_PERIOD as f64 / sample_rate;
+            self.coefficient = 1.0 - (-period / BEND_GLIDE_TIME).exp();
+        }
+        self.countdown = 0;
+        self.factor = 0.0;
+    }
+
+    #[inline]
+    fn tick(&mut self, input: &Frame<f64, U1>) -> Frame<f64, U1> {
+        if self.countdown == 0 {
+            self.countdown = CONTROL_PERIOD;
+            let target = input[0];
+            // the glide lands on the bend exactly, so a string at rest isn't retuned every update
+            self.factor = if self.factor == 0.0 || (target - self.factor).abs() < BEND_GLIDE_SNAP {
+                target
+            } else {
+                self.factor + (target - self.factor) * self.coefficient
+            };
+        }
+        self.countdown -= 1;
+        [self.factor].into()
+    }
+}
+
+/// Glide for the pitch bend of a string. It starts at the bend it is first given.
+/// - Input 0: pitch bend factor, as set by `VoiceControls::pitch_bend`
+/// - Output 0: glided factor to scale the fundamental by
+pub fn bend_glide() -> An<BendGlide> {
+    let mut node = BendGlide {
+        coefficient: 1.0,
+        countdown: 0,
+        factor: 0.0,
+    };
+    node.reset(Some(DEFAULT_SR));
+    An(node)
+}
+
+/// A one-pole lowpass that turns the tone of a voice down as its timbre falls.
+#[derive(Clone)]
+pub struct Tone {
+    sample_rate: f64,
+    // timbre the coefficient was worked out for, the coefficient and the filter state
+    timbre: f64,
+    coefficient: f64,
+    state: f64,
+}
+
+impl AudioNode for Tone {
+    const ID: u64 = 1020;
+    type Sample = f64;
+    type Inputs = U2;
+    type Outputs = U1;
+    type Setting = ();
+
+    fn reset(&mut self, sample_rate: Option<f64>) {
+        if let Some(sample_rate) = sample_rate {
+            self.sample_rate = sample_rate;
+        }
+        self.timbre = 1.0;
+        self.coefficient = 1.0;
+        self.state = 0.0;
+    }
+
+    #[inline]
+    fn tick(&mut self, input: &Frame<f64, U2>) -> Frame<f64, U1> {
+        if input[1] != self.timbre {
+            self.timbre = input[1];
+            let timbre = clamp(0.0, 1.0, self.timbre);
+            // at the top of the controller the tone is fully open and the filter passes the
+            // voice untouched
+            self.coefficient = if timbre < 1.0 {
+                let cutoff = TONE_MIN_CUTOFF * TONE_RANGE.powf(timbre);
+                1.0 - (-TAU * cutoff / self.sample_rate).exp()
+            } else {
+                1.0
+            };
+        }
+        self.state += (input[0] - self.state) * self.coefficient;
+        [self.state].into()
+    }
+}
+
+/// Tone control for the output of a voice.
+/// - Input 0: audio
+/// - Input 1: timbre (0 to 1), as set by `VoiceControls::timbre`
+/// - Output 0: filtered audio
+pub fn tone() -> An<Tone> {
+    let mut node = Tone {
+        sample_rate: DEFAULT_SR,
+        timbre: 1.0,
+        coefficient: 1.0,
+        state: 0.0,
+    };
+    node.reset(Some(DEFAULT_SR));
+    An(node)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bend_glide_ramps_to_the_bend_and_lands_on_it() {
+        let sample_rate = 44100.0;
+        let mut glide = bend_glide();
+        glide.reset(Some(sample_rate));
+        // the glide starts at the first bend it is given, and holds it until the next update
+        for _ in 0..CONTROL_PERIOD {
+            assert_eq!(glide.tick(&[1.0].into())[0], 1.0);
+        }
+
+        // after a step up to a whole octave, every update covers the distance left at the rate of
+        // `BEND_GLIDE_TIME`, until the glide snaps onto the bend
+        let period = CONTROL_PERIOD as f64 / sample_rate;
+        let factors: Vec<f64> = (0..(0.2 * sample_rate) as usize)
+            .map(|_| glide.tick(&[2.0].into())[0])
+            .collect();
+        for (i, factor) in factors.iter().enumerate() {
+            let updates = (i / CONTROL_PERIOD + 1) as f64;
+            let remaining = (-updates * period / BEND_GLIDE_TIME).exp();
+            if remaining > BEND_GLIDE_SNAP {
+                assert!(
+                    (2.0 - factor - remaining).abs() < 1.0e-9,
+                    "{factor} after {updates} updates, expected {}",
+                    2.0 - remaining
+                );
+            }
+        }
+        assert_eq!(factors.last(), Some(&2.0));
+    }
+
+    #[test]
+    fn vibrato_deepens_with_pressure() {
+        // over a second, the pitch swings either way by the depth the pressure asks for, and not at
+        // all without pressure
+        let sample_rate = 44100.0;
+        for pressure in [0.0, 0.25, 0.5, 1.0] {
+            let mut node = vibrato();
+            node.reset(Some(sample_rate));
+            let (low, high) = (0..sample_rate as usize)
+                .map(|_| 12.0 * node.tick(&[pressure].into())[0].log2())
+                .fold((0.0, 0.0), |(low, high): (f64, f64), semitones| {
+                    (low.min(semitones), high.max(semitones))
+                });
+            let depth = VIBRATO_DEPTH * pressure;
+            assert!(
+                (high - depth).abs() < 0.01 * VIBRATO_DEPTH
+                    && (low + depth).abs() < 0.01 * VIBRATO_DEPTH,
+                "{pressure} swings from {low} to {high} semitones"
+            );
+        }
+    }
+
+    #[test]
+    fn damping_shortens_the_decay() {
+        // an impulse round a lossless loop of 100 samples falls by 60 dB times the damping in
+        // `DAMPED_DECAY_TIME`
+        let sample_rate = 44100.0;
+        let length = 100;
+        let freq = sample_rate / length as f64;
+        for damping in [0.0, 0.25, 0.5, 1.0] {
+            let mut node = damper();
+            node.reset(Some(sample_rate));
+            let mut line = vec![0.0; length];
+            let mut output = vec![];
+            for i in 0..(2.0 * DAMPED_DECAY_TIME * sample_rate) as usize {
+                let impulse = if i == 0 { 1.0 } else { 0.0 };
+                let sample = node.tick(&[line[i % length], freq, damping].into())[0];
+                line[i % length] = sample + impulse;
+                output.push(line[i % length]);
+            }
+            let trips = (DAMPED_DECAY_TIME * freq).round() as usize;
+            let decibels = 20.0 * output[trips * length].abs().log10();
+            assert!(
+                (decibels + 60.0 * damping).abs() < 1.0,
+                "{damping} falls by {decibels} dB"
+            );
+        }
+    }
+
+    // Gain of the tone at `timbre` for a sine at `freq_hz`, once it has settled.
+    fn tone_gain(timbre: f64, freq_hz: f64) -> f64 {
+        let sample_rate = 44100.0;
+        let mut node = tone();
+        node.reset(Some(sample_rate));
+        let settled: Vec<f64> = (0..sample_rate as usize / 2)
+            .map(|i| {
+                let sine = (TAU * freq_hz * i as f64 / sample_rate).sin();
+                node.tick(&[sine, timbre].into())[0]
+            })
+            .skip(sample_rate as usize / 4)
+            .collect();
+        crate::peak(&settled)
+    }
+
+    #[test]
+    fn lower_timbre_lowers_the_cutoff() {
+        // the top of the controller leaves the voice untouched, and every step down turns a
+        // 1 kHz tone further down
+        assert!((tone_gain(1.0, 1000.0) - 1.0).abs() < 1.0e-3);
+        let gains = [0.75, 0.5, 0.25, 0.0].map(|timbre| tone_gain(timbre, 1000.0));
+        assert!(gains.windows(2).all(|pair| pair[1] < pair[0]), "{gains:?}");
+
+        // at the bottom, the cutoff is `TONE_MIN_CUTOFF`, where the lowpass is 3 dB down
+        let decibels = 20.0 * tone_gain(0.0, TONE_MIN_CUTOFF).log10();
+        assert!((decibels + 3.0).abs() < 0.5, "{decibels} dB at the cutoff");
+    }
+}
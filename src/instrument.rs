@@ -0,0 +1,249 @@
+//! Multi-string instruments.
+//!
+//! An `Instrument` is a set of strings, each with its own gauge and tuning, plus the number of
+//! frets that can shorten them. Each string is played by its own voice, and the `VoiceAllocator`
+//! maps incoming MIDI notes onto a string and fret. The fretted string is just the open
+//! `StringModel` with a shorter vibrating length, and so a stiffer one.
+
+use crate::string::{StringModel, NYLON_MODULUS, STEEL_MODULUS};
+use fundsp::hacker::midi_hz;
+
+/// Names of the built-in presets accepted by `Instrument::preset()`.
+pub static PRESETS: [&str; 4] = ["guitar", "bass", "mandolin", "harp"];
+
+// Densities of the string materials (kg/m^3), used to derive linear density from gauge.
+static STEEL_DENSITY: f64 = 7850.0;
+static NYLON_DENSITY: f64 = 1140.0;
+
+/// One string of an instrument, tuned to `open_note`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct InstrumentString {
+    pub open_note: u8,
+    pub model: StringModel,
+}
+
+impl InstrumentString {
+    /// Create a string of the given linear density (kg/m) and scale length (m), tensioned so the
+    /// open string sounds at MIDI note `open_note`.
+    pub fn new(open_note: u8, linear_density: f64, length: f64) -> Self {
+        Self {
+            open_note,
+            model: StringModel::tuned(midi_hz(open_note as f64), linear_density, length),
+        }
+    }
+
+    /// A plain steel string of the given gauge (inches), tuned to `open_note`.
+    pub fn plain(open_note: u8, gauge: f64, length: f64) -> Self {
+        Self::new(open_note, plain_steel(gauge), length).with_core(inches(gauge), STEEL_MODULUS)
+    }
+
+    /// A wound string of the given linear density (kg/m), wrapped around a steel core of
+    /// `core_gauge` (inches), tuned to `open_note`.
+    pub fn wound(open_note: u8, linear_density: f64, core_gauge: f64, length: f64) -> Self {
+        Self::new(open_note, linear_density, length).with_core(inches(core_gauge), STEEL_MODULUS)
+    }
+
+    /// The same string, stiffened by a core of the given diameter (m) and Young's modulus (Pa).
+    pub fn with_core(mut self, diameter: f64, youngs_modulus: f64) -> Self {
+        self.model.diameter = diameter;
+        self.model.youngs_modulus = youngs_modulus;
+        self
+    }
+}
+
+/// A set of strings that share a neck with `frets` frets.
+/// For a lever harp, `frets` is 1: each string's sharping lever raises it by a semitone.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Instrument {
+    pub name: &'static str,
+    pub strings: Vec<InstrumentString>,
+    pub frets: u8,
+}
+
+impl Instrument {
+    /// Look up a built-in preset by name (see `PRESETS`).
+    pub fn preset(name: &str) -> Option<Self> {
+        match name {
+            "guitar" => Some(Self::guitar()),
+            "bass" => Some(Self::bass()),
+            "mandolin" => Some(Self::mandolin()),
+            "harp" => Some(Self::harp()),
+            _ => None,
+        }
+    }
+
+    /// Six-string steel-string guitar in standard tuning (E2 A2 D3 G3 B3 E4), 25.5" scale,
+    /// light gauge (.010-.046) strings.
+    pub fn guitar() -> Self {
+        let scale = 0.648;
+        Self {
+            name: "guitar",
+            strings: vec![
+                InstrumentString::wound(40, 0.006825, 0.018, scale), // .046
+                InstrumentString::wound(45, 0.004280, 0.016, scale), // .036
+                InstrumentString::wound(50, 0.002263, 0.014, scale), // .026
+                InstrumentString::plain(55, 0.017, scale),
+                InstrumentString::plain(59, 0.013, scale),
+                InstrumentString::plain(64, 0.010, scale),
+            ],
+            frets: 22,
+        }
+    }
+
+    /// Four-string electric bass (E1 A1 D2 G2), 34" scale, .045-.105 roundwound strings.
+    pub fn bass() -> Self {
+        let scale = 0.864;
+        Self {
+            name: "bass",
+            strings: vec![
+                InstrumentString::wound(28, 0.0375, 0.040, scale), // .105
+                InstrumentString::wound(33, 0.0221, 0.034, scale), // .085
+                InstrumentString::wound(38, 0.0124, 0.028, scale), // .065
+                InstrumentString::wound(43, 0.0066, 0.020, scale), // .045
+            ],
+            frets: 20,
+        }
+    }
+
+    /// Mandolin (G3 D4 A4 E5), 13.9" scale, .011-.040 strings. Each pair of unison strings is
+    /// modelled as a single string.
+    pub fn mandolin() -> Self {
+        let scale = 0.353;
+        Self {
+            name: "mandolin",
+            strings: vec![
+                InstrumentString::wound(55, 0.0047, 0.014, scale), // .040
+                InstrumentString::wound(62, 0.00218, 0.011, scale), // .026
+                InstrumentString::plain(69, 0.015, scale),
+                InstrumentString::plain(76, 0.011, scale),
+            ],
+            frets: 20,
+        }
+    }
+
+    /// 34-string diatonic lever harp tuned to C major from C2 to A6, with nylon strings that get
+    /// shorter and thinner towards the treble.
+    pub fn harp() -> Self {
+        let count = 34;
+        let strings = (0..count)
+            .map(|i| {
+                let open_note = 36 + 12 * (i / 7) as u8 + [0, 2, 4, 5, 7, 9, 11][i % 7];
+                let freq = midi_hz(open_note as f64);
+                // bass strings are foreshortened relative to a constant-tension scaling
+                let length = (0.35 * (523.25 / freq).powf(0.8)).min(1.35);
+                let diameter = 1.8 - 1.2 * i as f64 / (count - 1) as f64; // mm
+                let linear_density = NYLON_DENSITY * circle_area(diameter / 1000.0);
+                InstrumentString::new(open_note, linear_density, length)
+                    .with_core(diameter / 1000.0, NYLON_MODULUS)
+            })
+            .collect();
+        Self {
+            name: "harp",
+            strings,
+            frets: 1,
+        }
+    }
+
+    /// The semitones `string` must be stopped above open to sound `note`, if it can reach it.
+    pub fn fret(&self, string: usize, note: u8) -> Option<u8> {
+        let fret = note.checked_sub(self.strings[string].open_note)?;
+        (fret <= self.frets).then_some(fret)
+    }
+
+    /// The string model of `string` stopped at `fret`: the same string with its vibrating length
+    /// shortened by a factor of `2^(-fret / 12)`.
+    pub fn fretted(&self, string: usize, fret: u8) -> StringModel {
+        let open = self.strings[string].model;
+        StringModel {
+            length: open.length * 2.0_f64.powf(-(fret as f64) / 12.0),
+            ..open
+        }
+    }
+
+    /// The open string models, in order, one per voice.
+    pub fn string_models(&self) -> Vec<StringModel> {
+        self.strings.iter().map(|s| s.model).collect()
+    }
+}
+
+/// The preset a General MIDI `program` (0 to 127) is played with, if any: the guitars (24 to 31)
+/// on the guitar, the basses (32 to 39) on the bass and the orchestral harp (46) on the harp.
+/// General MIDI has no mandolin, so the banjo (105) is played on it.
+pub fn program_preset(program: u8) -> Option<&'static str> {
+    match program {
+        24..=31 => Some("guitar"),
+        32..=39 => Some("bass"),
+        46 => Some("harp"),
+        105 => Some("mandolin"),
+        _ => None,
+    }
+}
+
+/// Linear density (kg/m) of a plain steel string of the given gauge (inches).
+fn plain_steel(gauge: f64) -> f64 {
+    STEEL_DENSITY * circle_area(inches(gauge))
+}
+
+/// Diameter (m) of a string of the given gauge (inches).
+fn inches(gauge: f64) -> f64 {
+    gauge * 0.0254
+}
+
+/// Cross-sectional area (m^2) of a round string of the given diameter (m).
+fn circle_area(diameter: f64) -> f64 {
+    std::f64::consts::PI * diameter * diameter / 4.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Whether `freq` is within a thousandth of a cent of MIDI `note`.
+    fn in_tune(freq: f64, note: u8) -> bool {
+        (1200.0 * (freq / midi_hz(note as f64)).log2()).abs() < 1e-3
+    }
+
+    #[test]
+    fn open_strings_are_in_tune() {
+        for name in PRESETS {
+            let instrument = Instrument::preset(name).unwrap();
+            assert_eq!(instrument.name, name);
+            for string in &instrument.strings {
+                assert!(
+                    in_tune(string.model.fundamental(), string.open_note),
+                    "{name}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn frets_raise_the_string_by_semitones() {
+        let guitar = Instrument::guitar();
+        let low_e = guitar.strings[0].open_note;
+        for note in low_e..=low_e + guitar.frets {
+            let fret = guitar.fret(0, note).unwrap();
+            assert!(in_tune(guitar.fretted(0, fret).fundamental(), note));
+        }
+        // below the open string, and above the last fret
+        assert_eq!(guitar.fret(0, low_e - 1), None);
+        assert_eq!(guitar.fret(0, low_e + guitar.frets + 1), None);
+    }
+
+    #[test]
+    fn programs_are_played_on_their_presets() {
+        let preset = |programs: &[u8]| {
+            programs
+                .iter()
+                .map(|&p| program_preset(p))
+                .collect::<Vec<_>>()
+        };
+        assert!(preset(&[24, 27, 31]).iter().all(|p| *p == Some("guitar")));
+        assert!(preset(&[32, 35, 39]).iter().all(|p| *p == Some("bass")));
+        assert_eq!(program_preset(46), Some("harp"));
+        assert_eq!(program_preset(105), Some("mandolin"));
+        assert!(preset(&[0, 23, 40, 45, 47, 104, 106, 127])
+            .iter()
+            .all(Option::is_none));
+    }
+}
@@ -0,0 +1,315 @@
+//! Loss filter for the waveguide loop.
+//!
+//! A real string loses its high partials faster than its low ones, so a flat gain in the loop
+//! sounds synthetic. `LoopFilter` sits in the feedback path of `create_sound()` and applies a loop
+//! gain together with a lowpass, so every trip around the loop darkens the tone a little more.
+//!
+//! The loss is applied once per trip around the loop, so a high note, with its short loop, loses
+//! more per second than a low one with the same gain. Instead, the string sets how long its
+//! partials ring in seconds, and `LossFilter::design()` works out the gain and lowpass for
+//! whatever fundamental the waveguide is tuned to. Every lowpass also delays the signal slightly,
+//! which `phase_delay()` reports so the waveguide can be shortened to stay in tune.
+
+use crate::string::StringModel;
+use fundsp::hacker::*;
+use std::f64::consts::PI;
+
+// Darkest brightness allowed. At 0, the pole of a one-pole filter reaches 1 and the loop goes
+// silent.
+static MIN_BRIGHTNESS: f64 = 0.01;
+
+// Highest loop gain at DC. Anything left at DC by the pluck would otherwise never die away.
+static MAX_LOOP_GAIN: f64 = 0.9999;
+
+/// Frequency at which `StringModel::high_decay_time` is measured (Hz).
+pub static HIGH_DECAY_FREQ: f64 = 4000.0;
+
+/// The lowpass applied on each trip around the loop.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum LossFilter {
+    /// Gain only: every partial decays at the same rate.
+    Flat,
+    /// The two-point average of the original Karplus-Strong algorithm. Always the same
+    /// brightness, and adds half a sample to the loop.
+    Average,
+    /// One-pole lowpass, set by the string's brightness or high-frequency decay time.
+    OnePole,
+    /// Two identical one-pole stages sharing the loss between them. Keeps the low partials
+    /// ringing longer and cuts the highest ones harder than a single pole, closer to the decay
+    /// times measured on real strings.
+    TwoPole,
+    /// Jaffe and Smith's decay-stretched two-point average, weighted towards the newer sample to
+    /// follow the string's brightness. The high partials ring far longer than with `average`.
+    Stretched,
+}
+
+/// Loop gain at DC and pole of each one-pole stage of a `LoopFilter`, for one fundamental. For a
+/// `Stretched` filter, the pole is the weight of the older sample instead.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LossCoefficients {
+    pub gain: f64,
+    pub pole: f64,
+}
+
+impl LossFilter {
+    /// Fit the loop filter to `string` playing at `freq_hz`, so the fundamental rings for the
+    /// string's `decay_time`. With a `high_decay_time`, partials at `HIGH_DECAY_FREQ` ring for
+    /// that long; otherwise the gain at the Nyquist frequency, relative to the fundamental, is
+    /// the string's brightness.
+    pub fn design(&self, string: &StringModel, freq_hz: f64, sample_rate: f64) -> LossCoefficients {
+        let omega = TAU * freq_hz / sample_rate;
+        let fundamental_gain = string.loop_gain(freq_hz);
+        let (ratio, at) = match string.high_loop_gain(freq_hz) {
+            Some(high_gain) => {
+                let high_omega = TAU * min(HIGH_DECAY_FREQ, 0.45 * sample_rate) / sample_rate;
+                (high_gain / fundamental_gain, high_omega)
+            }
+            None => (clamp(MIN_BRIGHTNESS, 1.0, string.brightness), PI),
+        };
+        let pole = match self {
+            // notes at or above the reference frequency have no partials to shape
+            _ if at <= omega => 0.0,
+            LossFilter::Flat | LossFilter::Average => 0.0,
+            LossFilter::OnePole => pole_for_gain(ratio, at),
+            LossFilter::TwoPole => pole_for_gain(ratio.sqrt(), at),
+            LossFilter::Stretched => stretch_for_gain(ratio, at),
+        };
+        LossCoefficients {
+            gain: min(fundamental_gain / self.response(pole, omega), MAX_LOOP_GAIN),
+            pole,
+        }
+    }
+
+    /// Gain of the lowpass, relative to DC, at `omega` (radians per sample).
+    fn response(&self, pole: f64, omega: f64) -> f64 {
+        let one_pole = (1.0 - pole) / (1.0 - 2.0 * pole * omega.cos() + pole * pole).sqrt();
+        match self {
+            LossFilter::Flat => 1.0,
+            LossFilter::Average => (0.5 * omega).cos().abs(),
+            LossFilter::OnePole => one_pole,
+            LossFilter::TwoPole => one_pole * one_pole,
+            LossFilter::Stretched => (1.0 - 2.0 * pole * (1.0 - pole) * (1.0 - omega.cos()))
+                .max(0.0)
+                .sqrt(),
+        }
+    }
+
+    /// Delay (samples) the lowpass with the given `pole` adds to a partial at `freq_hz`.
+    pub fn phase_delay(&self, pole: f64, freq_hz: f64, sample_rate: f64) -> f64 {
+        let omega = TAU * freq_hz / sample_rate;
+        let one_pole = (pole * omega.sin()).atan2(1.0 - pole * omega.cos()) / omega;
+        match self {
+            LossFilter::Flat => 0.0,
+            LossFilter::Average => 0.5,
+            LossFilter::OnePole => one_pole,
+            LossFilter::TwoPole => 2.0 * one_pole,
+            LossFilter::Stretched => {
+                (pole * omega.sin()).atan2(1.0 - pole + pole * omega.cos()) / omega
+            }
+        }
+    }
+}
+
+/// Pole `a` of a one-pole lowpass `(1 - a) / (1 - a z^-1)` whose gain at `omega` (radians per
+/// sample) is `ratio` times its gain at DC. Squaring the magnitude response gives a quadratic in
+/// `a`, and the smaller root is the stable one.
+fn pole_for_gain(ratio: f64, omega: f64) -> f64 {
+    if ratio >= 1.0 {
+        return 0.0;
+    }
+    let r2 = ratio * ratio;
+    let a = 1.0 - r2;
+    let b = 1.0 - r2 * omega.cos();
+    (b - (b * b - a * a).max(0.0).sqrt()) / a
+}
+
+/// Weight `s` of the older sample in a two-point average `(1 - s) + s z^-1` whose gain at `omega`
+/// (radians per sample) is `ratio`. The squared gain is `1 - 2 s (1 - s) (1 - cos omega)`, which
+/// gives a quadratic in `s`; the smaller root keeps the filter weighted towards the newer sample.
+/// Gains below what an even average reaches give an even average.
+fn stretch_for_gain(ratio: f64, omega: f64) -> f64 {
+    if ratio >= 1.0 {
+        return 0.0;
+    }
+    let product = min((1.0 - ratio * ratio) / (2.0 * (1.0 - omega.cos())), 0.25);
+    0.5 * (1.0 - (1.0 - 4.0 * product).sqrt())
+}
+
+/// Loop gain followed by a lowpass loss filter, redesigned by `LossFilter::design()` whenever the
+/// fundamental changes.
+#[derive(Clone)]
+pub struct LoopFilter {
+    filter: LossFilter,
+    string: StringModel,
+    sample_rate: f64,
+    freq: f64,
+    coefficients: LossCoefficients,
+    // previous input, and previous output of each stage
+    x1: f64,
+    y1: f64,
+    y2: f64,
+}
+
+impl AudioNode for LoopFilter {
+    const ID: u64 = 1004;
+    type Sample = f64;
+    type Inputs = U2;
+    type Outputs = U1;
+    type Setting = ();
+
+    fn reset(&mut self, sample_rate: Option<f64>) {
+        if let Some(sample_rate) = sample_rate {
+            self.sample_rate = sample_rate;
+            // redesign on the next sample
+            self.freq = 0.0;
+        }
+        self.x1 = 0.0;
+        self.y1 = 0.0;
+        self.y2 = 0.0;
+    }
+
+    #[inline]
+    fn tick(&mut self, input: &Frame<f64, U2>) -> Frame<f64, U1> {
+        if input[1] != self.freq {
+            self.freq = input[1];
+            self.coefficients = self
+                .filter
+                .design(&self.string, self.freq, self.sample_rate);
+        }
+        let x = input[0] * self.coefficients.gain;
+        let a = self.coefficients.pole;
+        let output = match self.filter {
+            LossFilter::Flat => x,
+            LossFilter::Average => {
+                let output = 0.5 * (x + self.x1);
+                self.x1 = x;
+                output
+            }
+            LossFilter::OnePole => {
+                self.y1 = (1.0 - a) * x + a * self.y1;
+                self.y1
+            }
+            LossFilter::TwoPole => {
+                self.y1 = (1.0 - a) * x + a * self.y1;
+                self.y2 = (1.0 - a) * self.y1 + a * self.y2;
+                self.y2
+            }
+            LossFilter::Stretched => {
+                let output = (1.0 - a) * x + a * self.x1;
+                self.x1 = x;
+                output
+            }
+        };
+        [output].into()
+    }
+}
+
+/// Loss filter for the feedback path of `string`, fitted to the fundamental it is playing.
+/// - Input 0: audio
+/// - Input 1: fundamental (Hz)
+/// - Output 0: filtered audio
+pub fn loop_filter(filter: LossFilter, string: &StringModel) -> An<LoopFilter> {
+    let mut node = LoopFilter {
+        filter,
+        string: *string,
+        sample_rate: DEFAULT_SR,
+        freq: 0.0,
+        coefficients: LossCoefficients {
+            gain: 0.0,
+            pole: 0.0,
+        },
+        x1: 0.0,
+        y1: 0.0,
+        y2: 0.0,
+    };
+    node.reset(Some(DEFAULT_SR));
+    An(node)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amplitude;
+
+    static FILTERS: [LossFilter; 5] = [
+        LossFilter::Flat,
+        LossFilter::Average,
+        LossFilter::OnePole,
+        LossFilter::TwoPole,
+        LossFilter::Stretched,
+    ];
+
+    // Strings as dark and as bright as the options allow, with and without a high decay time.
+    fn strings() -> Vec<StringModel> {
+        let open = StringModel::tuned(110.0, 0.000477, 0.64);
+        let mut strings = vec![];
+        for brightness in [0.0, 0.5, 1.0] {
+            for high_decay_time in [None, Some(0.2), Some(10.0)] {
+                for decay_time in [0.5, 4.0, 1000.0] {
+                    strings.push(StringModel {
+                        brightness,
+                        high_decay_time,
+                        decay_time,
+                        ..open
+                    });
+                }
+            }
+        }
+        strings
+    }
+
+    #[test]
+    fn loop_filters_never_amplify() {
+        let sample_rate = 44100.0;
+        for filter in FILTERS {
+            for string in strings() {
+                for freq in [30.0, 110.0, 440.0, 2000.0, 8000.0] {
+                    let LossCoefficients { gain, pole } = filter.design(&string, freq, sample_rate);
+                    for i in 0..=1000 {
+                        let omega = PI * i as f64 / 1000.0;
+                        let response = gain * filter.response(pole, omega);
+                        assert!(
+                            response <= 1.0,
+                            "{filter:?} at {freq} Hz gains {response} at {omega} rad"
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn fundamental_rings_for_the_decay_time() {
+        // a loop of 200 samples, plucked with noise, rings at about 220 Hz
+        let sample_rate = 44100.0;
+        let length = 200;
+        let freq = sample_rate / length as f64;
+        let string = StringModel {
+            decay_time: 1.0,
+            ..StringModel::tuned(freq, 0.000477, 0.64)
+        };
+        for filter in FILTERS {
+            let mut node = loop_filter(filter, &string);
+            node.reset(Some(sample_rate));
+            let mut samples: Vec<f64> = (0..length).map(|i| rnd(i as i64) - 0.5).collect();
+            for i in length..(sample_rate as usize) {
+                let output = node.tick(&[samples[i - length], freq].into())[0];
+                samples.push(output);
+            }
+
+            // the filter lengthens the loop a little, lowering the pitch it rings at
+            let pole = filter.design(&string, freq, sample_rate).pole;
+            let ringing =
+                sample_rate / (length as f64 + filter.phase_delay(pole, freq, sample_rate));
+            let window = 4410;
+            let early = amplitude(&samples[4410..4410 + window], ringing, sample_rate);
+            let late = amplitude(&samples[26460..26460 + window], ringing, sample_rate);
+            let decay_rate = 20.0 * (early / late).log10() / 0.5;
+            let decay_time = 60.0 / decay_rate;
+            assert!(
+                (decay_time - string.decay_time).abs() < 0.03 * string.decay_time,
+                "{filter:?} rings for {decay_time} s"
+            );
+        }
+    }
+}
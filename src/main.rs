@@ -2,102 +2,698 @@
 
 use anyhow::bail;
 use assert_no_alloc::*;
-use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
-use cpal::{Device, FromSample, SampleFormat, SizedSample, StreamConfig};
+use clap::Parser;
+use cpal::traits::{DeviceTrait, StreamTrait};
+use cpal::{Device, FromSample, SampleFormat, SizedSample, Stream, StreamConfig, StreamError};
 use fundsp::hacker::*;
 use fundsp::prelude::AudioUnit64;
-use midi_msg::{ChannelVoiceMsg, MidiMsg};
-use midir::{Ignore, MidiInput, MidiInputPort};
+use midi_msg::{ChannelModeMsg, ChannelVoiceMsg, ControlChange, MidiMsg};
 use read_input::prelude::*;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::Duration;
+
+mod audio;
+mod bidirectional;
+mod body;
+mod channel;
+mod cli;
+mod coupling;
+mod dispersion;
+mod error;
+mod excitation;
+mod expression;
+mod instrument;
+mod loss;
+mod midi;
+mod mpe;
+mod notes;
+mod pickup;
+mod program;
+mod render;
+mod smf;
+mod string;
+mod variant;
+mod voice;
+mod waveguide;
+
+use audio::{buffer_size, choose_config, choose_device, choose_host, print_devices};
+use bidirectional::{bidirectional, Waveguide};
+use body::apply_body;
+use cli::{AudioArgs, Cli, Command, MidiArgs, SoundArgs, StringArgs};
+use coupling::{bridge_drive, bridge_tap};
+use dispersion::dispersion;
+use error::Error;
+use excitation::{exciter, MAX_PLUCK_POSITION};
+use expression::{bend_glide, damper, tone, vibrato};
+use instrument::{Instrument, PRESETS};
+use loss::{loop_filter, LossFilter};
+use midi::{connect_inputs, list_ports, PortSelection};
+use mpe::LOWER_MANAGER;
+use pickup::{pickup, Pickup};
+use program::{program_switch, ProgramSwitch, SynthSlot};
+use render::{duration, read_note_list, render_to_wav, BitDepth, EventPlayer, ScheduledEvent};
+use smf::read_midi_file;
+use string::StringModel;
+use variant::{bow, sign_flip, Variant};
+use voice::{create_mix, level_meter, retrigger, VoiceAllocator, VoiceControls};
+use waveguide::{fractional_delay, loop_tuning};
 
 #[cfg(debug_assertions)] // required when disable_release is set (default)
 #[global_allocator]
 static A: AllocDisabler = AllocDisabler;
 
-// Globally defined string parameters.
-// Update these to modify what string is played, or to "tune" the existing string.
-static TENSION: f64 = 48.86; // B string tension (N)
-static LINEAR_DENSITY: f64 = 0.000477; // B string linear density (Kg/m)
-static STRING_LENGTH: f64 = 0.64; // string length (meters)
+// Range of the waveguide delay in seconds. The longest loop fits MIDI note 0 (~8.2 Hz), the
+// shortest reaches MIDI note 127 while leaving the interpolation of `fractional_delay()` enough
+// samples.
+static MIN_LOOP_DELAY: f64 = 0.00005;
+static MAX_LOOP_DELAY: f64 = 0.125;
+
+// How long file playback and offline renders keep going after the last event, so the final
+// notes can ring out (s).
+static RENDER_TAIL: f64 = 2.0;
+
+// Sample rate of offline renders when none is given (Hz).
+static DEFAULT_RENDER_RATE: u32 = 44100;
 
-// Main call that runs when program starts
+// How many program changes in a file being played can wait for their synth to be built. Sending
+// one never allocates, as the space is set aside before playback starts.
+static PROGRAM_REQUESTS: usize = 16;
+
+// Main call that runs when program starts.
+// Parses the command line (see `cli.rs`) and runs the chosen subcommand. With no subcommand, it
+// plays live from MIDI input.
 fn main() -> anyhow::Result<()> {
-    let mut midi_in = MidiInput::new("midir reading input")?;
-    let in_port = get_midi_device(&mut midi_in)?;
+    let cli = Cli::parse();
+    match cli.command.unwrap_or(Command::Play { file: None }) {
+        Command::Play { file: None } => run_live(&cli.audio, &cli.midi, &cli.strings, &cli.sound),
+        Command::Play { file: Some(path) } => run_play(&path, &cli.audio, &cli.strings, &cli.sound),
+        Command::Render {
+            input,
+            output,
+            bit_depth,
+        } => run_render(
+            &input,
+            &output,
+            bit_depth,
+            &cli.audio,
+            &cli.strings,
+            &cli.sound,
+        ),
+        Command::Devices => run_devices(),
+        Command::Tune { measure } => run_tune(measure, &cli.audio, &cli.strings, &cli.sound),
+    }
+}
 
-    // set up shared variables
-    let pitch = shared(0.0);
-    let volume = shared(0.0);
-    let pitch_bend = shared(1.0);
-    let control = shared(0.0);
+// Plays live from the selected MIDI input through the audio device, until enter is pressed.
+fn run_live(
+    audio: &AudioArgs,
+    midi: &MidiArgs,
+    strings: &StringArgs,
+    sound: &SoundArgs,
+) -> anyhow::Result<()> {
+    let selection = choose_midi_port(midi)?;
+
+    // set up the voice pool, each voice holding its own shared variables and string
+    let (voices, models) = create_voices(strings)?;
 
     // initialize output
-    run_output(
-        pitch.clone(),
-        volume.clone(),
-        pitch_bend.clone(),
-        control.clone(),
-    );
+    let synth = run_output(audio, sound, voices.controls(), models, None)?;
 
     // initialize midi input (non-blocking)
-    run_input(midi_in, in_port, pitch, volume, pitch_bend, control)
+    run_input(&selection, midi.virtual_port.as_deref(), voices, synth)
+}
+
+// Sets up the voice pool for the `--preset` instrument, or a pool of `--voices` identical strings
+// when it is `none`, returning it with the string model of each voice. Every preset is set up
+// the same way for program changes to switch to. Notes are plucked at `--pluck-position`, and
+// aftertouch is applied as `--aftertouch`.
+fn create_voices(args: &StringArgs) -> anyhow::Result<(VoiceAllocator, Vec<StringModel>)> {
+    let programs = PRESETS
+        .iter()
+        .filter_map(|name| customize_preset(name, args))
+        .collect();
+    let (voices, models) = match args.preset.as_str() {
+        "none" => {
+            let mut model =
+                StringModel::tuned(midi_hz(args.open_note as f64), args.density, args.length);
+            customize(&mut model, args);
+            (
+                VoiceAllocator::new(args.voices, args.steal),
+                vec![model; max(args.voices, 1)],
+            )
+        }
+        name => {
+            let Some(instrument) = customize_preset(name, args) else {
+                bail!("Unknown preset '{name}', expected one of {PRESETS:?} or none")
+            };
+            println!("Playing {}", instrument.name);
+            let models = instrument.string_models();
+            (
+                VoiceAllocator::for_instrument(instrument, args.steal),
+                models,
+            )
+        }
+    };
+    let mut voices = voices
+        .with_programs(programs)
+        .with_aftertouch(args.aftertouch)
+        .with_bend_range(args.bend_range)
+        .with_pluck_position(args.pluck_position);
+    if args.mpe {
+        voices.configure_zone(LOWER_MANAGER, 15);
+    }
+    Ok((voices, models))
+}
+
+// Looks up the preset `name`, with every string customized by `customize()`.
+fn customize_preset(name: &str, args: &StringArgs) -> Option<Instrument> {
+    let mut instrument = Instrument::preset(name)?;
+    instrument
+        .strings
+        .iter_mut()
+        .for_each(|string| customize(&mut string.model, args));
+    Some(instrument)
+}
+
+// Overrides the defaults of a string with `--decay-time`, `--high-decay-time`, `--brightness`,
+// `--diameter` and `--youngs-modulus`, where they are given.
+fn customize(model: &mut StringModel, args: &StringArgs) {
+    if let Some(decay_time) = args.decay_time {
+        model.decay_time = decay_time;
+    }
+    if args.high_decay_time.is_some() {
+        model.high_decay_time = args.high_decay_time;
+    }
+    if let Some(brightness) = args.brightness {
+        model.brightness = brightness;
+    }
+    if let Some(diameter) = args.diameter {
+        model.diameter = diameter;
+    }
+    if let Some(youngs_modulus) = args.youngs_modulus {
+        model.youngs_modulus = youngs_modulus;
+    }
+}
+
+// Reads the events of a Standard MIDI File (.mid or .midi) or, for any other extension, a note list.
+fn read_events(path: &Path) -> anyhow::Result<Vec<ScheduledEvent>> {
+    let extension = path.extension().map(|e| e.to_ascii_lowercase());
+    match extension.as_ref().and_then(|e| e.to_str()) {
+        Some("mid" | "midi") => read_midi_file(path),
+        _ => read_note_list(path),
+    }
+}
+
+// Plays a MIDI file or note list through the audio device. The events are handled inside the
+// audio callback, so they land on the exact sample they are scheduled for.
+fn run_play(
+    path: &Path,
+    audio: &AudioArgs,
+    strings: &StringArgs,
+    sound: &SoundArgs,
+) -> anyhow::Result<()> {
+    let events = read_events(path)?;
+    let length = duration(&events) + RENDER_TAIL;
+
+    let (voices, models) = create_voices(strings)?;
+    run_output(
+        audio,
+        sound,
+        voices.controls(),
+        models,
+        Some((events, voices)),
+    )?;
+
+    println!("Playing {} ({length:.1} s)", path.display());
+    std::thread::sleep(std::time::Duration::from_secs_f64(length));
+    Ok(())
+}
+
+// Renders a MIDI file or note list to a WAV file without touching any audio or MIDI device.
+fn run_render(
+    input: &Path,
+    output: &Path,
+    bit_depth: BitDepth,
+    audio: &AudioArgs,
+    strings: &StringArgs,
+    sound: &SoundArgs,
+) -> anyhow::Result<()> {
+    let sample_rate = audio.sample_rate.unwrap_or(DEFAULT_RENDER_RATE);
+    let events = read_events(input)?;
+    let (mut voices, models) = create_voices(strings)?;
+    let (mut synth, builder) =
+        SynthBuilder::build(sound, &voices.controls(), &models, sample_rate as f64);
+    render_to_wav(
+        output,
+        events,
+        &mut synth,
+        |msg| handle_message(&mut voices, &builder, msg),
+        sample_rate,
+        bit_depth,
+        RENDER_TAIL,
+    )?;
+    println!("Rendered {} to {}", input.display(), output.display());
+    Ok(())
+}
+
+// Lists the output devices of every audio host, then the MIDI input ports.
+fn run_devices() -> anyhow::Result<()> {
+    print_devices()?;
+    print_midi_ports()?;
+    Ok(())
+}
+
+// Prints the open tuning of every string, with the length of its waveguide loop in samples.
+// With `measure`, also checks the tuning of the synth with `measure_tuning()`.
+fn run_tune(
+    measure: bool,
+    audio: &AudioArgs,
+    strings: &StringArgs,
+    sound: &SoundArgs,
+) -> anyhow::Result<()> {
+    let sample_rate = audio.sample_rate.unwrap_or(DEFAULT_RENDER_RATE);
+    let (voices, models) = create_voices(strings)?;
+    println!("string  note  frequency (Hz)  tension (N)  velocity (m/s)  loop ({sample_rate} Hz)");
+    for (index, string) in models.iter().enumerate() {
+        let freq = string.fundamental();
+        println!(
+            "{:>6}  {:>4}  {:>14.2}  {:>11.1}  {:>14.1}  {:>8.2} samples",
+            index + 1,
+            note_name(freq),
+            freq,
+            string.tension,
+            string.wave_velocity(),
+            string.round_trip() * sample_rate as f64,
+        );
+    }
+    if measure {
+        measure_tuning(voices, &models, sound, sample_rate as f64);
+    }
+    Ok(())
+}
+
+// Plays each MIDI note from 21 (A0) to 120 (C9) on its own through the synth, offline, skipping
+// notes the instrument can't reach, and prints how far the measured pitch of each one is from
+// equal temperament.
+fn measure_tuning(
+    mut voices: VoiceAllocator,
+    strings: &[StringModel],
+    sound: &SoundArgs,
+    sample_rate: f64,
+) {
+    let mut synth = create_synth(&voices.controls(), strings, sound, sample_rate);
+    let skip = (0.1 * sample_rate) as usize;
+    let length = sample_rate as usize;
+    let mut worst: f64 = 0.0;
+    println!("\nnote  expected (Hz)  measured (Hz)  error (cents)");
+    for note in 21..=120 {
+        synth.reset(Some(sample_rate));
+        if voices.note_on(0, note, 100).is_none() {
+            continue;
+        }
+        let samples: Vec<f64> = (0..skip + length)
+            .map(|_| synth.get_stereo().0)
+            .skip(skip)
+            .collect();
+        voices.note_off(0, note);
+
+        let expected = midi_hz(note as f64);
+        let measured = measure_frequency(&samples, expected, sample_rate);
+        let cents = 1200.0 * (measured / expected).log2();
+        worst = worst.max(cents.abs());
+        println!(
+            "{:>4}  {expected:>13.2}  {measured:>13.2}  {cents:>+13.2}",
+            note_name(expected)
+        );
+    }
+    println!("largest error: {worst:.2} cents");
+}
+
+// Finds the frequency within half a semitone of `expected` where `samples` have the most energy,
+// by evaluating the Hann-windowed spectrum on a 1 cent grid and then a 0.02 cent grid around the
+// best match.
+fn measure_frequency(samples: &[f64], expected: f64, sample_rate: f64) -> f64 {
+    let level = |freq: f64| amplitude(samples, freq, sample_rate);
+    let best = |centre: f64, step: f64, steps: i32| {
+        (-steps..=steps)
+            .map(|i| centre * 2.0_f64.powf(i as f64 * step / 1200.0))
+            .max_by(|a, b| level(*a).total_cmp(&level(*b)))
+            .unwrap_or(centre)
+    };
+    let coarse = best(expected, 1.0, 50);
+    best(coarse, 0.02, 50)
+}
+
+// Amplitude of `samples` at `freq_hz`, from their Hann-windowed spectrum.
+fn amplitude(samples: &[f64], freq_hz: f64, sample_rate: f64) -> f64 {
+    let (mut re, mut im) = (0.0, 0.0);
+    for (i, sample) in samples.iter().enumerate() {
+        let window = 0.5 - 0.5 * (TAU * i as f64 / samples.len() as f64).cos();
+        let phase = TAU * freq_hz * i as f64 / sample_rate;
+        re += sample * window * phase.cos();
+        im += sample * window * phase.sin();
+    }
+    (re * re + im * im).sqrt()
+}
+
+// Largest magnitude of `samples`.
+#[cfg(test)]
+fn peak(samples: &[f64]) -> f64 {
+    samples.iter().fold(0.0, |peak: f64, x| peak.max(x.abs()))
+}
+
+// Name and octave of the equal-tempered note nearest to `freq_hz`, e.g. "A4" for 440 Hz.
+fn note_name(freq_hz: f64) -> String {
+    let names = [
+        "C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B",
+    ];
+    let note = (69.0 + 12.0 * (freq_hz / 440.0).log2()).round() as i32;
+    format!(
+        "{}{}",
+        names[note.rem_euclid(12) as usize],
+        note.div_euclid(12) - 1
+    )
+}
+
+// Builds the complete synth: one string per voice from `create_sound()`, summed on a mix bus and
+// heard through the `--body` or `--body-ir`, if there is one.
+fn create_synth(
+    voices: &[VoiceControls],
+    strings: &[StringModel],
+    options: &SoundArgs,
+    sample_rate: f64,
+) -> Box<dyn AudioUnit64> {
+    let mix = create_mix(voices, |i, voice| {
+        let others = voices
+            .iter()
+            .enumerate()
+            .filter(|(j, _)| *j != i)
+            .map(|(_, other)| other.bridge.clone())
+            .collect();
+        create_sound(voice, &strings[i], others, options, sample_rate)
+    });
+    apply_body(mix, options.body, options.body_ir.as_ref())
+}
+
+// What it takes to build the synth again for another instrument, when a program change switches
+// to one: the options and sample rate it was built with, and the slot of the `ProgramSwitch` that
+// plays it. A builder used on the audio thread leaves the building to another thread, sending it
+// the index of each program to switch to instead.
+#[derive(Clone)]
+struct SynthBuilder {
+    sound: SoundArgs,
+    sample_rate: f64,
+    slot: SynthSlot,
+    requests: Option<mpsc::SyncSender<usize>>,
+}
+
+impl SynthBuilder {
+    // Builds the synth with `create_synth()`, played through a `ProgramSwitch`.
+    fn build(
+        sound: &SoundArgs,
+        voices: &[VoiceControls],
+        strings: &[StringModel],
+        sample_rate: f64,
+    ) -> (An<ProgramSwitch>, Self) {
+        let (mut synth, slot) = program_switch(create_synth(voices, strings, sound, sample_rate));
+        synth.reset(Some(sample_rate));
+        let builder = Self {
+            sound: sound.clone(),
+            sample_rate,
+            slot,
+            requests: None,
+        };
+        (synth, builder)
+    }
+
+    // A builder for the audio thread, which sends its requests to the returned receiver for this
+    // builder to `switch()` to.
+    fn deferred(&self) -> (Self, mpsc::Receiver<usize>) {
+        let (requests, received) = mpsc::sync_channel(PROGRAM_REQUESTS);
+        let builder = Self {
+            requests: Some(requests),
+            ..self.clone()
+        };
+        (builder, received)
+    }
+
+    // Switches to the program of `voices` at `index`, as returned by
+    // `VoiceAllocator::program_change()`. A deferred builder only sends the index, which doesn't
+    // allocate.
+    fn program_change(&self, voices: &VoiceAllocator, index: usize) {
+        if let Some(requests) = &self.requests {
+            let _ = requests.try_send(index);
+            return;
+        }
+        let (voices, strings) = voices.program_voices(index);
+        self.switch(&voices, &strings);
+    }
+
+    // Builds the synth for `voices` playing `strings`, and hands it to the `ProgramSwitch` to
+    // crossfade to.
+    fn switch(&self, voices: &[VoiceControls], strings: &[StringModel]) {
+        let mut synth = create_synth(voices, strings, &self.sound, self.sample_rate);
+        synth.reset(Some(self.sample_rate));
+        self.slot.put(synth);
+    }
 }
 
 /// (Partially from fundsp/examples/live_adsr.rs)
-/// This function is where the `adsr_live()` function is employed. The `shared()` objects are wrapped
-/// in `var()` objects in order to be placed in the signal graph.
-/// * The `adsr_live()` modulates the volume of the sound over time. Play around with the different
-///   values to get a feel for the impact of different ADSR levels. The `control` `shared()` is set
-///   to 1.0 to start the attack and 0.0 to start the release.
-/// * Then, we modulate the volume further using the MIDI velocity.
-/// * Note that the `pitch` and `pitch_bend` parameters are not used currently.
-///   Additional work would need to be done in order to integrate midi, as currently the
-///   frequency produced is determined by a waveguide (delay) of fixed timing, and this would need to
-///   be updated when a note changes or a new guitar string would need to be spawned.
+/// This function builds the signal graph of one string. The `shared()` objects are wrapped in
+/// `var()` objects in order to be placed in the signal graph.
+/// * The string is plucked by `exciter()`, which writes a burst (chosen by `--excitation`) into
+///   the loop whenever the `control` `shared()` goes to 1.0. `retrigger()` makes it pluck again
+///   whenever the voice is re-plucked.
+/// * The MIDI velocity sets both the level and the brightness of the burst, and the
+///   `pluck_position` filters out the partials that have a node where the string is plucked.
+/// * The `pitch` (scaled by `pitch_bend`, glided by `bend_glide()`, and shaken by `vibrato()`)
+///   sets the fundamental of the string. The waveguide is a `fractional_delay()`, which
+///   interpolates between samples (as chosen by `--interpolation`) so the loop can take any
+///   fractional length, and the delay is retuned every sample to match whichever note was played
+///   last. Until the first note arrives, the string rings at the open
+///   pitch of the `StringModel`.
+/// * The feedback passes through `loop_filter()`, which applies a loop gain and a lowpass (chosen
+///   by `--loss-filter`) so the high partials die away before the low ones. Both are derived from
+///   the string's decay times for the current fundamental, so every note rings for as long.
+/// * It then passes through `dispersion()`, a cascade of allpasses that stretches the partials
+///   of a stiff string sharp of the harmonic series. `loop_tuning()` takes the delay of both
+///   filters off the waveguide, and corrects for the interpolator, to keep the string in tune.
+/// * Finally, the feedback passes through `damper()`, which cuts the decay of the string short as
+///   aftertouch presses on it, and mutes it once its note is released, or at once on All Sound
+///   Off.
+/// * With `--waveguide bidirectional`, the single loop is replaced by the two rails of
+///   `bidirectional()`, which reflect the wave at the nut and at the bridge (where it passes
+///   through the same `loop_filter()` and `dispersion()`), are excited at the pluck position, and
+///   are read at the pickup coils.
+/// * `--variant` chooses the Karplus-Strong algorithm. `harp` swaps the loss filter for a
+///   decay-stretched average, `drum` passes the feedback through `sign_flip()`, and `bowed`
+///   replaces the excitation and waveguide with the `bow()`, which keeps the string sounding for as
+///   long as the note is held.
+/// * With `--coupling`, the string is also driven by the `others` on the bridge through
+///   `bridge_drive()`, and writes itself to the bridge with `bridge_tap()`, so strings left ringing
+///   resonate in sympathy with the notes played on the others.
+/// * The string is then finished by `string_output()`.
 fn create_sound(
-    pitch: Shared<f64>,
-    volume: Shared<f64>,
-    pitch_bend: Shared<f64>,
-    control: Shared<f64>,
+    voice: &VoiceControls,
+    string: &StringModel,
+    others: Vec<Shared<f64>>,
+    options: &SoundArgs,
+    sample_rate: f64,
 ) -> Box<dyn AudioUnit64> {
-    // (experimental) get pitch from midi, handle div by zero errors
-    // let mut freq = var(&pitch).value();
-    // if freq == 0.0 {
-    //     freq = freq + 1.0;
-    // }
+    let open_freq_hz = string.fundamental();
+
+    // get fundamental from midi, bent smoothly and shaken by the vibrato, falling back to the open
+    // string before any note is played
+    let fundamental = (var(&voice.pitch)
+        * (var(&voice.pitch_bend) >> bend_glide())
+        * (var(&voice.vibrato) >> vibrato()))
+        >> map(
+            move |f: &Frame<f64, U1>| {
+                if f[0] > 0.0 {
+                    f[0]
+                } else {
+                    open_freq_hz
+                }
+            },
+        );
+
+    // the string is damped by aftertouch, and fully once its note is released, as a lifted finger
+    // or the damper of a piano would
+    let damping = (var(&voice.damping) | var(&voice.control))
+        >> map(|f: &Frame<f64, U2>| max(f[0], 1.0 - f[1]));
+
+    // the harp stretches the decay with its own loss filter, and the drum flips the sign of the
+    // loop at random
+    let loss = match options.variant {
+        Variant::Harp => LossFilter::Stretched,
+        _ => options.loss_filter,
+    };
+    let blend = match options.variant {
+        Variant::Drum => options.drum_blend,
+        _ => 1.0,
+    };
+
+    // a bowed string is driven by its bow for as long as the note is held, at the pluck position
+    if options.variant == Variant::Bowed {
+        let pluck = ((var(&voice.control) >> retrigger(&voice.trigger))
+            | fundamental.clone()
+            | var(&voice.volume)
+            | var(&voice.pluck_position)
+            | damping)
+            >> bow(string, loss, options.bow_pressure, MAX_LOOP_DELAY)
+            >> bridge_tap(&voice.bridge);
+        let pickup = options
+            .pickup
+            .map(|position| pickup(position, options.pickup_type, Some(string.round_trip())));
+        return string_output(pluck, fundamental, pickup, voice, string, sample_rate);
+    }
+
+    // generate the excitation at the start of every note; the bidirectional waveguide reflects
+    // it off the ends of the string by itself
+    let excitation = ((var(&voice.control) >> retrigger(&voice.trigger))
+        | fundamental.clone()
+        | var(&voice.volume)
+        | var(&voice.pluck_position))
+        >> exciter(options.excitation, options.waveguide == Waveguide::Single);
+
+    // the other strings drive this one through the bridge, so it rings in sympathy with them
+    let excitation =
+        excitation + (fundamental.clone() >> bridge_drive(others, options.coupling, string));
 
-    // compute effective waveguide length
-    let velocity = sqrt(TENSION / LINEAR_DENSITY);
-    let waveguide_length = 2.0 * STRING_LENGTH / velocity;
-    let waveguide = delay(waveguide_length);
+    match options.waveguide {
+        Waveguide::Single => {
+            // the feedback loop adds one sample of latency and the loss filter and dispersion a
+            // little more, so the delay is shortened to compensate
+            let waveguide = (pass()
+                | fundamental.clone()
+                    >> loop_tuning(
+                        loss,
+                        string,
+                        options.interpolation,
+                        MIN_LOOP_DELAY,
+                        MAX_LOOP_DELAY,
+                    ))
+                >> fractional_delay(options.interpolation, MIN_LOOP_DELAY, MAX_LOOP_DELAY);
 
-    // generate impulse
-    let impulse = dc(1.0)
-        * var(&volume)
-        * (var(&control) >> adsr_live(waveguide_length / 2., waveguide_length / 2., 0.0, 0.0));
+            // loss in the feedback - each time the sample passes through it gets multipled by
+            // the loop gain and lowpassed, both fitted to the string's decay times at the
+            // current fundamental
+            let loss_filter = (pass() | fundamental.clone()) >> loop_filter(loss, string);
 
-    // options for feedback gain - each time the sample passes through it gets multipled by 0.995
-    let feedback_gain = mul(0.995);
+            // stiffness in the feedback - the allpass cascade lets the high partials round the
+            // loop sooner, stretching them as on a stiff string
+            let stiffness = (pass() | fundamental.clone()) >> dispersion(string);
 
-    // generate feedback with a delay loop
-    let string_feedback = feedback2(waveguide, feedback_gain);
+            // damping in the feedback - aftertouch can lean on the string to cut its decay short,
+            // and releasing the note mutes it
+            let damping = (pass() | fundamental.clone() | damping) >> damper();
 
-    // pluck the string by passing the impulse into the delay loop
-    let pluck = impulse >> string_feedback;
+            // generate feedback with a delay loop
+            let string_feedback = feedback2(
+                waveguide,
+                loss_filter >> stiffness >> sign_flip(blend) >> damping,
+            );
+
+            // pluck the string by passing the excitation into the delay loop, and let the other
+            // strings hear it on the bridge
+            let pluck = excitation >> string_feedback >> bridge_tap(&voice.bridge);
+
+            let pickup = options
+                .pickup
+                .map(|position| pickup(position, options.pickup_type, Some(string.round_trip())));
+            string_output(pluck, fundamental, pickup, voice, string, sample_rate)
+        }
+        Waveguide::Bidirectional => {
+            // the rails are read where the pickup coils sit, or at the bridge without a pickup
+            let (taps, coils) = match options.pickup {
+                Some(position) => options.pickup_type.coils(position),
+                None => ([0.0; 2], 0),
+            };
+            let rails = bidirectional(
+                string,
+                loss,
+                options.excitation,
+                &taps[..coils],
+                blend,
+                MAX_LOOP_DELAY / 2.0,
+            );
+            let pluck = (excitation | fundamental.clone() | var(&voice.pluck_position) | damping)
+                >> rails
+                >> bridge_tap(&voice.bridge);
+
+            let pickup = options
+                .pickup
+                .map(|position| pickup(position, options.pickup_type, None));
+            string_output(pluck, fundamental, pickup, voice, string, sample_rate)
+        }
+    }
+}
 
-    // generate resonant harmonics by filtering impulse
+/// Finishes the sound of a string playing at `fundamental`.
+/// * With a `--pickup`, the string is read by `pickup()`, which hears it at one point along its
+///   length and filters it through the resonance of a single coil or humbucker, and that is the
+///   whole sound.
+/// * Otherwise, bandpassed harmonics are added at the partials of the string, which are stretched
+///   when it has stiffness.
+/// * The sound is then passed through `tone()`, which turns it down as the `timbre` falls.
+/// * Finally, the output level is metered so the voice allocator can find the quietest voice.
+fn string_output<S, F>(
+    pluck: An<S>,
+    fundamental: An<F>,
+    pickup: Option<An<Pickup>>,
+    voice: &VoiceControls,
+    string: &StringModel,
+    sample_rate: f64,
+) -> Box<dyn AudioUnit64>
+where
+    S: AudioNode<Sample = f64, Inputs = U0, Outputs = U1> + Send + Sync + 'static,
+    F: AudioNode<Sample = f64, Inputs = U0, Outputs = U1> + Send + Sync + 'static,
+{
+    // an electric string is only heard through its pickup
+    if let Some(pickup) = pickup {
+        let sound = ((pluck >> pickup) | var(&voice.timbre)) >> tone() >> level_meter(&voice.level);
+        return Box::new(sound);
+    }
+
+    // generate resonant harmonics by filtering the string, with centres following the fundamental.
+    // A bandpass tuned above the Nyquist frequency is unstable, so harmonics of high notes that
+    // fall above it are held just below and muted.
     let harmonic_q = 10.0;
-    let root_freq_hz = waveguide_length.powi(-1);
+    let highest_harmonic = 0.45 * sample_rate;
+    let harmonic = |n: f64| {
+        let model = *string;
+        let centre = fundamental.clone()
+            >> map(move |f: &Frame<f64, U1>| f[0] * model.partial_ratio(n, f[0]));
+        ((pluck.clone()
+            | centre.clone() >> map(move |f: &Frame<f64, U1>| min(f[0], highest_harmonic))
+            | dc(harmonic_q))
+            >> bandpass())
+            * (centre
+                >> map(
+                    move |f: &Frame<f64, U1>| {
+                        if f[0] < highest_harmonic {
+                            1.0
+                        } else {
+                            0.0
+                        }
+                    },
+                ))
+    };
 
-    // // these should be feedbacks instead, but we need to generate an impulse, not constant tone
-    let harmonic_2 = pluck.clone() >> bandpass_hz(root_freq_hz * 2.0, harmonic_q) * 1.0;
-    let harmonic_3 = pluck.clone() >> bandpass_hz(root_freq_hz * 3., harmonic_q) * 0.5;
-    let harmonic_4 = pluck.clone() >> bandpass_hz(root_freq_hz * 4., harmonic_q) * 0.5;
-    let harmonic_5 = pluck.clone() >> bandpass_hz(root_freq_hz * 5., harmonic_q) * 0.3;
-    let harmonic_6 = pluck.clone() >> bandpass_hz(root_freq_hz * 6., harmonic_q) * 0.2;
+    // // these should be feedbacks instead, but we need to generate a pluck, not constant tone
+    let harmonic_2 = harmonic(2.0) * 1.0;
+    let harmonic_3 = harmonic(3.0) * 0.5;
+    let harmonic_4 = harmonic(4.0) * 0.5;
+    let harmonic_5 = harmonic(5.0) * 0.3;
+    let harmonic_6 = harmonic(6.0) * 0.2;
 
     // chain signals together into path
-    let sound = pluck + harmonic_2 + harmonic_3 + harmonic_4 + harmonic_5 + harmonic_6;
+    let sound = ((pluck + harmonic_2 + harmonic_3 + harmonic_4 + harmonic_5 + harmonic_6)
+        | var(&voice.timbre))
+        >> tone()
+        >> level_meter(&voice.level);
 
     // (experimental) limiting, dc control, and declicking for safety
     // let mut sound = sound >> (declick() | declick()) >> (dcblock() | dcblock());
@@ -105,148 +701,350 @@ fn create_sound(
     Box::new(sound)
 }
 
-// (From fundsp/examples/live_adsr.rs)
-// Gets midi devices from host
-fn get_midi_device(midi_in: &mut MidiInput) -> anyhow::Result<MidiInputPort> {
-    midi_in.ignore(Ignore::None);
-    let in_ports = midi_in.ports();
-    if in_ports.is_empty() {
-        bail!("No MIDI devices attached")
-    } else {
-        println!(
-            "Chose MIDI device {}",
-            midi_in.port_name(&in_ports[0]).unwrap()
-        );
-        Ok(in_ports[0].clone())
+// Prints the available MIDI input ports with their indices, returning how many there are.
+fn print_midi_ports() -> anyhow::Result<usize> {
+    let names = list_ports()?;
+    println!("MIDI input ports:");
+    for (index, name) in names.iter().enumerate() {
+        println!("  {index}: {name}");
+    }
+    Ok(names.len())
+}
+
+// Picks the MIDI input to play from: `--midi-port` if given, otherwise the only port, or one the user
+// chooses from the list when there are several.
+fn choose_midi_port(midi: &MidiArgs) -> anyhow::Result<PortSelection> {
+    if let Some(spec) = &midi.midi_port {
+        return Ok(PortSelection::parse(spec));
     }
+    Ok(match print_midi_ports()? {
+        0 => PortSelection::None,
+        1 => PortSelection::Index(0),
+        count => PortSelection::Index(
+            input::<usize>()
+                .msg("Choose a MIDI input port: ")
+                .inside(0..count)
+                .get(),
+        ),
+    })
 }
 
 /// (From fundsp/examples/live_adsr.rs)
-/// This function is where MIDI events control the values of the `shared()` objects.
-/// * A `NoteOn` event alters all four `shared()` objects:
-///   * Using `midi_hz()`, a MIDI pitch is converted to a frequency and stored.
-///   * MIDI velocity values range from 0 to 127. We divide by 127 and store in `volume`.
-///   * Setting `pitch_bend` to 1.0 makes the bend neutral.
-///   * Setting `control` to 1.0 starts the attack.
-/// * A `NoteOff` event sets `control` to 0.0 to start the release.
-/// * A `PitchBend` event calls `pitch_bend_factor()` to convert the MIDI values into
-///   a scaling factor for the pitch, which it stores in `pitch_bend`.
+/// This function opens the selected MIDI input ports (and a virtual port named `virtual_port`, if
+/// given) and passes every message they receive to `handle_message()`, until enter is pressed.
+/// Messages that can't be parsed (such as unsupported system exclusive messages) are skipped.
+/// A program change builds the synth for the new instrument here, off the audio thread.
 fn run_input(
-    midi_in: MidiInput,
-    in_port: MidiInputPort,
-    pitch: Shared<f64>,
-    volume: Shared<f64>,
-    pitch_bend: Shared<f64>,
-    control: Shared<f64>,
+    selection: &PortSelection,
+    virtual_port: Option<&str>,
+    mut voices: VoiceAllocator,
+    synth: SynthBuilder,
 ) -> anyhow::Result<()> {
     println!("\nOpening connection");
-    let in_port_name = midi_in.port_name(&in_port)?;
-    let _conn_in = midi_in
-        .connect(
-            &in_port,
-            "midir-read-input",
-            move |_stamp, message, _| {
-                let (msg, _len) = MidiMsg::from_midi(message).unwrap();
-                if let MidiMsg::ChannelVoice { channel: _, msg } = msg {
-                    println!("Received {msg:?}");
-                    match msg {
-                        ChannelVoiceMsg::NoteOn { note, velocity } => {
-                            pitch.set_value(midi_hz(note as f64));
-                            volume.set_value(velocity as f64 / 127.0);
-                            pitch_bend.set_value(1.0);
-                            control.set_value(1.0);
-                        }
-                        ChannelVoiceMsg::NoteOff { note, velocity: _ } => {
-                            if pitch.value() == midi_hz(note as f64) {
-                                control.set_value(-1.0);
-                            }
-                        }
-                        ChannelVoiceMsg::PitchBend { bend } => {
-                            pitch_bend.set_value(pitch_bend_factor(bend));
-                        }
-                        _ => {}
-                    }
-                }
-            },
-            (),
-        )
-        .unwrap();
-    println!("Connection open, reading input from '{in_port_name}'");
+    let _connections = connect_inputs(selection, virtual_port, move |message| {
+        let msg = match MidiMsg::from_midi(message) {
+            Ok((msg, _len)) => msg,
+            Err(err) => {
+                eprintln!("Skipping MIDI message {message:02X?}: {err}");
+                return;
+            }
+        };
+        handle_message(&mut voices, &synth, &msg);
+    })?;
+    println!("Connection open, reading input");
 
     let _ = input::<String>().msg("(press enter to exit)...\n").get();
     println!("Closing connection");
     Ok(())
 }
 
+/// (Partially from fundsp/examples/live_adsr.rs)
+/// This function is where MIDI events control the values of the `shared()` objects. The
+/// `VoiceAllocator` picks which voice's objects each event is written to. Live input, file playback
+/// and offline rendering all go through this function.
+/// * A `NoteOn` event is assigned a voice (stealing one if they are all busy) and alters its
+///   `shared()` objects. Notes that no string of the instrument can reach are ignored.
+///   * The MIDI pitch is converted to a frequency and stored. Without an instrument this uses
+///     `midi_hz()`; with one, it is the fundamental of the string stopped at the chosen fret.
+///   * MIDI velocity values range from 0 to 127. We divide by 127 and store in `volume`.
+///   * `pitch_bend` is set to the current bend of the channel.
+///   * Setting `control` to 1.0 starts the attack.
+/// * A `NoteOff` event, or a `NoteOn` with a velocity of 0, lifts the key of the note. The
+///   `NoteTracker` follows each note from held to sustained to released: a note whose key comes up
+///   is sustained while the sustain pedal (CC 64) is down, or the sostenuto pedal (CC 66) went down
+///   while it was held, and released once no pedal holds it. Releasing a note sets `control` to
+///   0.0 to start the release of every voice playing it.
+/// * While the soft pedal (CC 67) of a channel is down, its notes are played softer.
+/// * A `ControlChange` of CC 70 (sound variation) calls `pluck_position()` to convert its value
+///   into the pluck position of the notes of its channel that follow.
+/// * A `PitchBend` event bends the voices playing the notes of its channel, by up to the bend
+///   range of the channel (2 semitones either way unless `--bend-range` or RPN 0 sets it). Every
+///   channel keeps its own bend and range in a `ChannelState`, and `bend_glide()` glides each
+///   voice to its new bend.
+/// * Controllers 6, 38 and 96 to 101 select, set and step registered parameters; only the bend
+///   range (RPN 0) is supported.
+/// * `PolyPressure` sets the `vibrato` or `damping` (as chosen by `--aftertouch`) of the voices
+///   playing its note, and `ChannelPressure` of every sounding voice of its channel.
+/// * CC 74 (timbre) sets the `timbre` of the voices of its channel, which turns their tone down.
+/// * Every message applies to the notes of its own channel. In an MPE zone, set up by `--mpe` or
+///   by an MPE Configuration Message (RPN 6), each note has a member channel of its own, so its
+///   bend, pressure and timbre reach only the voice playing it, and the messages on the manager
+///   channel apply to every note of the zone.
+/// * A `ProgramChange` to one of the General MIDI programs of a preset switches to it: `synth`
+///   builds the strings of the new instrument and crossfades to them.
+/// * All Notes Off releases every held note of its channel, All Sound Off silences every string
+///   of its channel at once, and Reset All Controllers lifts the pedals and takes the aftertouch
+///   and the pitch bend of its channel back to rest.
+fn handle_message(voices: &mut VoiceAllocator, synth: &SynthBuilder, msg: &MidiMsg) {
+    let (channel, msg) = match msg {
+        MidiMsg::ChannelVoice { channel, msg } => (*channel, *msg),
+        MidiMsg::ChannelMode { channel, msg } => {
+            let index = *channel as usize;
+            match msg {
+                // changing mode also turns every note off
+                ChannelModeMsg::AllNotesOff
+                | ChannelModeMsg::OmniMode(_)
+                | ChannelModeMsg::PolyMode(_) => voices.all_notes_off(index),
+                ChannelModeMsg::AllSoundOff => voices.all_sound_off(index),
+                ChannelModeMsg::ResetAllControllers => voices.reset_controllers(index),
+                _ => {}
+            }
+            return;
+        }
+        _ => return,
+    };
+    let index = channel as usize;
+    match msg {
+        // many keyboards send a note-on with no velocity when a key comes up
+        ChannelVoiceMsg::NoteOn { note, velocity: 0 }
+        | ChannelVoiceMsg::NoteOff { note, velocity: _ } => {
+            voices.note_off(index, note);
+        }
+        ChannelVoiceMsg::NoteOn { note, velocity } => {
+            voices.note_on(index, note, velocity);
+        }
+        ChannelVoiceMsg::ControlChange { control } => match control {
+            ControlChange::Hold(value) => voices.sustain(index, value >= 64),
+            ControlChange::Sostenuto(value) => voices.sostenuto(index, value >= 64),
+            ControlChange::SoftPedal(value) => voices.soft_pedal(index, value >= 64),
+            ControlChange::SoundControl1(value) => {
+                voices.pluck_position(index, pluck_position(value))
+            }
+            // every message holds a single controller, so data entry only carries its coarse
+            // value, and the fine value and the parameter numbers arrive as undefined controllers
+            ControlChange::DataEntry(value) => voices.controller(index, 6, (value >> 7) as u8),
+            ControlChange::DataEntry2(coarse, fine) => {
+                voices.controller(index, 6, coarse);
+                voices.controller(index, 38, fine);
+            }
+            ControlChange::DataIncrement(value) => voices.controller(index, 96, value),
+            ControlChange::DataDecrement(value) => voices.controller(index, 97, value),
+            ControlChange::SoundControl5(value) | ControlChange::Brightness(value) => {
+                voices.controller(index, 74, value)
+            }
+            ControlChange::Undefined { control, value } => voices.controller(index, control, value),
+            _ => {}
+        },
+        ChannelVoiceMsg::PitchBend { bend } => {
+            voices.pitch_bend(index, bend);
+        }
+        ChannelVoiceMsg::PolyPressure { note, pressure } => {
+            voices.note_pressure(index, note, pressure as f64 / 127.0);
+        }
+        ChannelVoiceMsg::ChannelPressure { pressure } => {
+            voices.channel_pressure(index, pressure as f64 / 127.0);
+        }
+        ChannelVoiceMsg::ProgramChange { program } => {
+            if let Some(index) = voices.program_change(program) {
+                synth.program_change(voices, index);
+            }
+        }
+        _ => {}
+    }
+}
+
 // (From fundsp/examples/live_adsr.rs)
-// This function figures out the sample format and calls `run_synth()` accordingly.
+// This function opens the `--device` output of the `--host` audio API (or the defaults), finds a
+// configuration for the requested sample rate and buffer size, and calls `run_synth()` with the
+// device's sample format.
+// When playing a file, `sequence` holds its events and the voice pool that handles them.
+// Returns what it takes to switch the synth to another instrument.
 fn run_output(
-    pitch: Shared<f64>,
-    volume: Shared<f64>,
-    pitch_bend: Shared<f64>,
-    control: Shared<f64>,
-) {
-    let host = cpal::default_host();
-    let device = host
-        .default_output_device()
-        .expect("failed to find a default output device");
-    let config = device.default_output_config().unwrap();
-    match config.sample_format() {
-        SampleFormat::F32 => {
-            run_synth::<f32>(pitch, volume, pitch_bend, control, device, config.into())
+    audio: &AudioArgs,
+    sound: &SoundArgs,
+    voices: Vec<VoiceControls>,
+    strings: Vec<StringModel>,
+    sequence: Option<(Vec<ScheduledEvent>, VoiceAllocator)>,
+) -> Result<SynthBuilder, Error> {
+    let host = choose_host(audio)?;
+    let device = choose_device(&host, audio)?;
+    let supported = choose_config(&device, audio)?;
+    let format = supported.sample_format();
+    let config = StreamConfig {
+        buffer_size: buffer_size(audio),
+        ..supported.config()
+    };
+    println!(
+        "Playing through '{}' ({}) at {} Hz, {format}",
+        device.name()?,
+        host.id().name(),
+        config.sample_rate.0
+    );
+    let (audio, sound) = (audio.clone(), sound.clone());
+    match format {
+        SampleFormat::I8 => {
+            run_synth::<i8>(audio, sound, voices, strings, sequence, device, config)
         }
         SampleFormat::I16 => {
-            run_synth::<i16>(pitch, volume, pitch_bend, control, device, config.into())
+            run_synth::<i16>(audio, sound, voices, strings, sequence, device, config)
+        }
+        SampleFormat::I32 => {
+            run_synth::<i32>(audio, sound, voices, strings, sequence, device, config)
+        }
+        SampleFormat::I64 => {
+            run_synth::<i64>(audio, sound, voices, strings, sequence, device, config)
+        }
+        SampleFormat::U8 => {
+            run_synth::<u8>(audio, sound, voices, strings, sequence, device, config)
         }
         SampleFormat::U16 => {
-            run_synth::<u16>(pitch, volume, pitch_bend, control, device, config.into())
+            run_synth::<u16>(audio, sound, voices, strings, sequence, device, config)
+        }
+        SampleFormat::U32 => {
+            run_synth::<u32>(audio, sound, voices, strings, sequence, device, config)
+        }
+        SampleFormat::U64 => {
+            run_synth::<u64>(audio, sound, voices, strings, sequence, device, config)
+        }
+        SampleFormat::F32 => {
+            run_synth::<f32>(audio, sound, voices, strings, sequence, device, config)
+        }
+        SampleFormat::F64 => {
+            run_synth::<f64>(audio, sound, voices, strings, sequence, device, config)
         }
-        _ => panic!("Unsupported format"),
+        format => Err(Error::UnsupportedFormat(format)),
     }
 }
 
 /// (From fundsp/examples/live_adsr.rs)
 /// This function is where the sound is created and played. Once the sound is playing, it loops
 /// infinitely, allowing the `shared()` objects to shape the sound in response to MIDI events.
+/// When playing a file, its events are handled just before the sample they are scheduled for.
+/// * The stream is built on its own thread, which reports whether it started before this function
+///   returns.
+/// * If the device disconnects, the stream is rebuilt on the device chosen by `audio` (once it is
+///   available again) with the same configuration, and the synth carries on where it left off.
+/// * The synth is played through a `ProgramSwitch`, and the `SynthBuilder` that switches it is
+///   returned once the stream has started. A program change in a file being played is handled in
+///   the audio callback, so it only requests the new synth, which this thread builds for the voices
+///   of the program, taken from the voice pool before the stream starts. This thread also drops
+///   the synths the `ProgramSwitch` has faded out.
 fn run_synth<T: SizedSample + FromSample<f64>>(
-    pitch: Shared<f64>,
-    volume: Shared<f64>,
-    pitch_bend: Shared<f64>,
-    control: Shared<f64>,
+    audio: AudioArgs,
+    sound: SoundArgs,
+    voices: Vec<VoiceControls>,
+    strings: Vec<StringModel>,
+    sequence: Option<(Vec<ScheduledEvent>, VoiceAllocator)>,
     device: Device,
     config: StreamConfig,
-) {
+) -> Result<SynthBuilder, Error> {
+    let (started, start_result) = mpsc::sync_channel(1);
     std::thread::spawn(move || {
         let sample_rate = config.sample_rate.0 as f64;
-        let mut sound = create_sound(pitch, volume, pitch_bend, control);
-        sound.reset(Some(sample_rate));
-
-        let mut next_value = move || sound.get_stereo();
-        let channels = config.channels as usize;
-        let err_fn = |err| eprintln!("an error occurred on stream: {err}");
-        let stream = device
-            .build_output_stream(
-                &config,
-                move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
-                    write_data(data, channels, &mut next_value)
-                },
-                err_fn,
-                None,
-            )
-            .unwrap();
+        let (mut synth, builder) = SynthBuilder::build(&sound, &voices, &strings, sample_rate);
 
-        stream.play().unwrap();
+        let (player_builder, requests) = builder.deferred();
+        let programs = sequence
+            .as_ref()
+            .map_or_else(Vec::new, |(_, voices)| voices.programs());
+        let mut sequence =
+            sequence.map(|(events, voices)| (EventPlayer::new(events, sample_rate), voices));
+        let next_value = Arc::new(Mutex::new(move || {
+            if let Some((player, voices)) = &mut sequence {
+                player.advance(|msg| handle_message(voices, &player_builder, msg));
+            }
+            synth.get_stereo()
+        }));
+        let disconnected = Arc::new(AtomicBool::new(false));
+
+        let mut stream = match build_stream::<T, _>(&device, &config, &next_value, &disconnected) {
+            Ok(stream) => stream,
+            Err(err) => {
+                let _ = started.send(Err(err));
+                return;
+            }
+        };
+        let _ = started.send(Ok(builder.clone()));
         loop {
-            std::thread::sleep(std::time::Duration::from_millis(1));
+            std::thread::sleep(Duration::from_millis(1));
+            for index in requests.try_iter() {
+                let (voices, strings) = &programs[index];
+                builder.switch(voices, strings);
+            }
+            builder.slot.collect();
+            if !disconnected.swap(false, Ordering::Relaxed) {
+                continue;
+            }
+            eprintln!("Audio device disconnected, reconnecting...");
+            drop(stream);
+            stream = loop {
+                let reconnect = choose_host(&audio)
+                    .and_then(|host| choose_device(&host, &audio))
+                    .and_then(|device| {
+                        build_stream::<T, _>(&device, &config, &next_value, &disconnected)
+                    });
+                match reconnect {
+                    Ok(stream) => break stream,
+                    Err(err) => {
+                        eprintln!("Failed to reconnect to the audio device: {err}");
+                        std::thread::sleep(Duration::from_secs(1));
+                    }
+                }
+            };
+            println!("Audio device reconnected");
         }
     });
+    start_result
+        .recv()
+        .unwrap_or(Err(Error::AudioThreadStopped))
 }
 
-/// (From fundsp/examples/live_adsr.rs)
-/// Algorithm is from here: https://sites.uci.edu/camp2014/2014/04/30/managing-midi-pitchbend-messages/
-/// Converts MIDI pitch-bend message to +/- 1 semitone.
-fn pitch_bend_factor(bend: u16) -> f64 {
-    2.0_f64.powf(((bend as f64 - 8192.0) / 8192.0) / 12.0)
+// Builds and starts an output stream on `device` that pulls its samples from `next_value`.
+// When the device goes away, `disconnected` is set so `run_synth()` can rebuild the stream.
+fn build_stream<T, F>(
+    device: &Device,
+    config: &StreamConfig,
+    next_value: &Arc<Mutex<F>>,
+    disconnected: &Arc<AtomicBool>,
+) -> Result<Stream, Error>
+where
+    T: SizedSample + FromSample<f64>,
+    F: FnMut() -> (f64, f64) + Send + 'static,
+{
+    let channels = config.channels as usize;
+    let next_value = Arc::clone(next_value);
+    let disconnected = Arc::clone(disconnected);
+    let err_fn = move |err| match err {
+        StreamError::DeviceNotAvailable => disconnected.store(true, Ordering::Relaxed),
+        err => eprintln!("an error occurred on stream: {err}"),
+    };
+    let stream = device.build_output_stream(
+        config,
+        move |data: &mut [T], _: &cpal::OutputCallbackInfo| match next_value.lock() {
+            Ok(mut next_value) => write_data(data, channels, &mut *next_value),
+            Err(_) => data.fill(T::EQUILIBRIUM),
+        },
+        err_fn,
+        None,
+    )?;
+    stream.play()?;
+    Ok(stream)
+}
+
+// Converts a MIDI controller value into a pluck position, from the bridge at 0 to the middle of
+// the string at 127.
+fn pluck_position(value: u8) -> f64 {
+    MAX_PLUCK_POSITION * value as f64 / 127.0
 }
 
 fn write_data<T>(output: &mut [T], channels: usize, next_sample: &mut dyn FnMut() -> (f64, f64))
@@ -267,3 +1065,424 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static SAMPLE_RATE: f64 = 44100.0;
+
+    // The synth built from the command line `args`, as `twang` builds it at 44.1 kHz, with its
+    // voices and the builder that switches its programs.
+    fn synth(args: &[&str]) -> (VoiceAllocator, An<ProgramSwitch>, SynthBuilder) {
+        let cli = Cli::parse_from(["twang"].iter().chain(args));
+        let (voices, models) = create_voices(&cli.strings).unwrap();
+        let (synth, builder) =
+            SynthBuilder::build(&cli.sound, &voices.controls(), &models, SAMPLE_RATE);
+        (voices, synth, builder)
+    }
+
+    // Plays a recording of raw MIDI messages on a guitar through `handle_message()`, returning its
+    // voices.
+    fn play(recording: &[&[u8]]) -> VoiceAllocator {
+        let (mut voices, _, synth) = synth(&["--preset", "guitar"]);
+        for bytes in recording {
+            let (msg, _len) = MidiMsg::from_midi(bytes).unwrap();
+            handle_message(&mut voices, &synth, &msg);
+        }
+        voices
+    }
+
+    // Plays the same recording saved as a MIDI file, through `read_midi_file()` and an
+    // `EventPlayer`, as `twang play` and `twang render` do.
+    fn play_file(recording: &[&[u8]]) -> VoiceAllocator {
+        let path = std::env::temp_dir().join(format!(
+            "twang-{}-{:?}.mid",
+            std::process::id(),
+            std::thread::current().id()
+        ));
+        smf::save_recording(&path, recording).unwrap();
+        let events = read_midi_file(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let (mut voices, _, synth) = synth(&["--preset", "guitar"]);
+        let mut player = EventPlayer::new(events, SAMPLE_RATE);
+        player.advance(|msg| handle_message(&mut voices, &synth, msg));
+        voices
+    }
+
+    // Number of voices whose note hasn't been released.
+    fn sounding(voices: &VoiceAllocator) -> usize {
+        voices
+            .controls()
+            .iter()
+            .filter(|voice| voice.control.value() > 0.0)
+            .count()
+    }
+
+    // Plays the parts of a recording on a guitar through `handle_message()`, 50 ms apart, and
+    // returns the peak of what is heard from 0.1 s to 0.15 s after the last part.
+    fn hear(parts: &[&[&[u8]]]) -> f64 {
+        let (mut voices, mut synth, builder) = synth(&["--preset", "guitar"]);
+        for part in parts {
+            for bytes in *part {
+                let (msg, _len) = MidiMsg::from_midi(bytes).unwrap();
+                handle_message(&mut voices, &builder, &msg);
+            }
+            for _ in 0..(0.05 * SAMPLE_RATE) as usize {
+                synth.get_mono();
+            }
+        }
+        let heard: Vec<f64> = (0..(0.1 * SAMPLE_RATE) as usize)
+            .map(|_| synth.get_mono())
+            .skip((0.05 * SAMPLE_RATE) as usize)
+            .collect();
+        peak(&heard)
+    }
+
+    #[test]
+    fn note_on_without_velocity_releases_the_note() {
+        let voices = play(&[&[0x90, 64, 100], &[0x90, 64, 0]]);
+        assert_eq!(sounding(&voices), 0);
+        // the release doesn't re-pluck the string or silence it
+        let controls = voices.controls();
+        let triggers: f64 = controls.iter().map(|voice| voice.trigger.value()).sum();
+        assert_eq!(triggers, 1.0);
+        assert!(controls
+            .iter()
+            .any(|voice| voice.volume.value() == 100.0 / 127.0));
+    }
+
+    #[test]
+    fn bent_and_fretted_notes_are_released() {
+        // G4 is fretted on the top string, and bent while it is held
+        let voices = play(&[&[0x90, 67, 90], &[0xE0, 0x00, 0x60], &[0x80, 67, 64]]);
+        assert_eq!(sounding(&voices), 0);
+    }
+
+    #[test]
+    fn control_follows_held_sustained_and_released_notes() {
+        let control = |recording: &[&[u8]]| {
+            let voices = play(recording);
+            let controls = voices.controls();
+            let voice = controls.iter().find(|voice| voice.trigger.value() > 0.0);
+            voice.unwrap().control.value()
+        };
+        let (on, off) = ([0x90, 60, 100], [0x80, 60, 0]);
+        let (pedal_down, pedal_up) = ([0xB0, 64, 127], [0xB0, 64, 0]);
+        assert_eq!(control(&[&on]), 1.0);
+        assert_eq!(control(&[&on, &pedal_down, &off]), 1.0);
+        assert_eq!(control(&[&on, &pedal_down, &off, &pedal_up]), 0.0);
+        assert_eq!(control(&[&on, &off]), 0.0);
+    }
+
+    #[test]
+    fn pedals_defer_releases() {
+        let sustain = [0xB0, 64, 127];
+        let voices = play(&[&sustain, &[0x90, 60, 100], &[0x80, 60, 0]]);
+        assert_eq!(sounding(&voices), 1);
+        let voices = play(&[&sustain, &[0x90, 60, 100], &[0x80, 60, 0], &[0xB0, 64, 0]]);
+        assert_eq!(sounding(&voices), 0);
+
+        // the sostenuto pedal only holds the notes down when it was pressed
+        let sostenuto = [0xB0, 66, 127];
+        let voices = play(&[
+            &[0x90, 60, 100],
+            &sostenuto,
+            &[0x90, 64, 100],
+            &[0x80, 60, 0],
+            &[0x90, 64, 0],
+        ]);
+        assert_eq!(sounding(&voices), 1);
+    }
+
+    #[test]
+    fn pedals_keep_released_strings_ringing() {
+        let (on, off) = ([0x90, 60, 100], [0x80, 60, 0]);
+        let held = hear(&[&[&on]]);
+        assert!(held > 0.1);
+
+        // a note sustained by the pedal rings on as if held, and is damped once the pedal is up
+        let (sustain, sustain_up) = ([0xB0, 64, 127], [0xB0, 64, 0]);
+        let sustained = hear(&[&[&sustain, &on], &[&off]]);
+        assert!(sustained > 0.5 * held);
+        let released = hear(&[&[&sustain, &on], &[&off], &[&sustain_up]]);
+        assert!(released < 0.05 * held);
+        assert!(hear(&[&[&on], &[&off]]) < 0.05 * held);
+
+        // the sostenuto pedal only keeps ringing the notes held when it was pressed
+        let sostenuto = [0xB0, 66, 127];
+        let (later_on, later_off) = ([0x90, 64, 100], [0x80, 64, 0]);
+        let latched = hear(&[&[&on, &sostenuto], &[&off]]);
+        assert!(latched > 0.5 * held);
+        let unlatched = hear(&[&[&sostenuto, &later_on], &[&later_off]]);
+        assert!(unlatched < 0.05 * held);
+    }
+
+    #[test]
+    fn soft_pedal_softens_only_its_channel() {
+        let voices = play(&[&[0xB1, 67, 127], &[0x90, 64, 100], &[0x91, 60, 100]]);
+        let mut volumes: Vec<f64> = voices
+            .controls()
+            .iter()
+            .map(|voice| voice.volume.value())
+            .filter(|&volume| volume > 0.0)
+            .collect();
+        volumes.sort_by(f64::total_cmp);
+        assert_eq!(volumes, [0.6 * 100.0 / 127.0, 100.0 / 127.0]);
+    }
+
+    #[test]
+    fn all_notes_off_leaves_sustained_notes_to_all_sound_off() {
+        let notes_off = [0xB0, 123, 0];
+        let sound_off = [0xB0, 120, 0];
+        let voices = play(&[&[0xB0, 64, 127], &[0x90, 60, 100], &notes_off]);
+        assert_eq!(sounding(&voices), 1);
+        let voices = play(&[&[0xB0, 64, 127], &[0x90, 60, 100], &notes_off, &sound_off]);
+        assert_eq!(sounding(&voices), 0);
+
+        // and the sustained string is heard until then
+        let sustained = hear(&[&[&[0xB0, 64, 127], &[0x90, 60, 100]], &[&notes_off]]);
+        assert!(sustained > 0.1);
+        let silenced = hear(&[
+            &[&[0xB0, 64, 127], &[0x90, 60, 100]],
+            &[&notes_off, &sound_off],
+        ]);
+        assert!(silenced < 0.05 * sustained);
+    }
+
+    #[test]
+    fn measure_frequency_finds_a_sine() {
+        for freq in [82.41, 441.3, 2637.0] {
+            let samples: Vec<f64> = (0..SAMPLE_RATE as usize / 2)
+                .map(|i| (TAU * freq * i as f64 / SAMPLE_RATE).sin())
+                .collect();
+            // expected a little sharp, so the search has to move
+            let measured = measure_frequency(&samples, freq * 1.01, SAMPLE_RATE);
+            let cents = 1200.0 * (measured / freq).log2();
+            assert!(
+                cents.abs() < 0.05,
+                "{freq} Hz measured {cents:+.3} cents off"
+            );
+        }
+    }
+
+    #[test]
+    fn notes_are_in_tune() {
+        // every note across the range lands within half a cent of equal temperament, whichever
+        // way the waveguide interpolates
+        for interpolation in ["linear", "lagrange3", "lagrange5", "thiran"] {
+            let (mut voices, mut synth, _) =
+                synth(&["--voices", "1", "--interpolation", interpolation]);
+            for note in [28, 40, 52, 64, 76, 88, 100] {
+                // the high notes die away within a fraction of a second, so they are measured
+                // over the first hundred periods or so
+                let expected = midi_hz(note as f64);
+                let skip = (min(0.1, 10.0 / expected) * SAMPLE_RATE) as usize;
+                let length = (min(0.5, 100.0 / expected) * SAMPLE_RATE) as usize;
+                synth.reset(Some(SAMPLE_RATE));
+                voices.note_on(0, note, 100).unwrap();
+                let samples: Vec<f64> = (0..skip + length)
+                    .map(|_| synth.get_mono())
+                    .skip(skip)
+                    .collect();
+                voices.note_off(0, note);
+                let cents =
+                    1200.0 * (measure_frequency(&samples, expected, SAMPLE_RATE) / expected).log2();
+                assert!(
+                    cents.abs() < 0.5,
+                    "note {note} is {cents:+.2} cents off with {interpolation}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn every_variant_sounds() {
+        // a held note on each variant is audible, and never builds up into runaway feedback
+        for variant in ["pluck", "drum", "harp", "bowed"] {
+            let (mut voices, mut synth, _) = synth(&["--voices", "1", "--variant", variant]);
+            voices.note_on(0, 52, 100).unwrap();
+            let samples: Vec<f64> = (0..(0.5 * SAMPLE_RATE) as usize)
+                .map(|_| synth.get_mono())
+                .collect();
+            let (early, late) = samples.split_at((0.4 * SAMPLE_RATE) as usize);
+            let (early, late) = (peak(early), peak(late));
+            assert!(samples.iter().all(|x| x.is_finite()));
+            assert!(early > 0.01, "{variant} is silent");
+            assert!(late < 2.0 * early, "{variant} grows from {early} to {late}");
+        }
+    }
+
+    #[test]
+    fn released_notes_are_damped() {
+        // a fifth of a second after the note is played, a note released after 50 ms has died away
+        // long before the same note left held, even on the short-lived drum
+        for args in [
+            ["--variant", "pluck"],
+            ["--variant", "drum"],
+            ["--variant", "harp"],
+            ["--waveguide", "bidirectional"],
+        ] {
+            let late_peak = |release: bool| {
+                let (mut voices, mut synth, _) = synth(&["--voices", "1", args[0], args[1]]);
+                voices.note_on(0, 52, 100).unwrap();
+                for _ in 0..(0.05 * SAMPLE_RATE) as usize {
+                    synth.get_mono();
+                }
+                if release {
+                    voices.note_off(0, 52);
+                }
+                let late: Vec<f64> = (0..(0.15 * SAMPLE_RATE) as usize)
+                    .map(|_| synth.get_mono())
+                    .skip((0.1 * SAMPLE_RATE) as usize)
+                    .collect();
+                peak(&late)
+            };
+            let (held, released) = (late_peak(false), late_peak(true));
+            assert!(held > 1.0e-4, "{args:?} is silent while held");
+            assert!(
+                released < 0.05 * held,
+                "{args:?} only falls from {held} to {released} when released"
+            );
+        }
+    }
+
+    #[test]
+    fn rpn_0_sets_the_bend_range() {
+        // 12 semitones and 50 cents, then the wheel all the way up
+        let voices = play(&[
+            &[0xB0, 101, 0],
+            &[0xB0, 100, 0],
+            &[0xB0, 6, 12],
+            &[0xB0, 38, 50],
+            &[0x90, 64, 100],
+            &[0xE0, 0x7F, 0x7F],
+        ]);
+        let bend = 2.0_f64.powf(12.5 / 12.0 * 8191.0 / 8192.0);
+        assert!(voices
+            .controls()
+            .iter()
+            .any(|voice| (voice.pitch_bend.value() - bend).abs() < 1e-9));
+    }
+
+    #[test]
+    fn rpn_0_in_a_file_sets_the_bend_range() {
+        let voices = play_file(&[
+            &[0xB0, 101, 0],
+            &[0xB0, 100, 0],
+            &[0xB0, 6, 12],
+            &[0xB0, 38, 50],
+            &[0x90, 64, 100],
+            &[0xE0, 0x7F, 0x7F],
+        ]);
+        let bend = 2.0_f64.powf(12.5 / 12.0 * 8191.0 / 8192.0);
+        assert!(voices
+            .controls()
+            .iter()
+            .any(|voice| (voice.pitch_bend.value() - bend).abs() < 1e-9));
+    }
+
+    #[test]
+    fn program_changes_in_a_file_do_not_allocate() {
+        // from the guitar to the bass and back, as the audio callback of file playback would
+        let (mut voices, _, builder) = synth(&["--preset", "guitar"]);
+        let programs = voices.programs();
+        let (player_builder, requests) = builder.deferred();
+        let (bass, _) = MidiMsg::from_midi(&[0xC0, 32]).unwrap();
+        let (guitar, _) = MidiMsg::from_midi(&[0xC0, 24]).unwrap();
+        assert_no_alloc(|| {
+            handle_message(&mut voices, &player_builder, &bass);
+            handle_message(&mut voices, &player_builder, &guitar);
+        });
+        let requested: Vec<usize> = requests.try_iter().collect();
+        assert_eq!(requested.len(), 2);
+        let (bass, bass_strings) = &programs[requested[0]];
+        let (guitar, guitar_strings) = &programs[requested[1]];
+        assert_eq!((bass.len(), bass_strings.len()), (4, 4));
+        assert_eq!((guitar.len(), guitar_strings.len()), (6, 6));
+        // the synth built from the requested voices is the one the notes now play
+        voices.note_on(0, 64, 100).unwrap();
+        assert!(guitar.iter().any(|voice| voice.control.value() == 1.0));
+        assert!(bass.iter().all(|voice| voice.control.value() == 0.0));
+    }
+
+    // An MPE Configuration Message giving the lower zone every other channel.
+    static MPE_LOWER_ZONE: [[u8; 3]; 3] = [[0xB0, 101, 0], [0xB0, 100, 6], [0xB0, 6, 15]];
+
+    #[test]
+    fn mpe_bend_reaches_only_the_note_on_its_channel() {
+        let [rpn_coarse, rpn_fine, members] = &MPE_LOWER_ZONE;
+        let voices = play(&[
+            rpn_coarse,
+            rpn_fine,
+            members,
+            &[0x91, 64, 100],
+            &[0x92, 67, 100],
+            &[0xE1, 0x00, 0x50],
+        ]);
+        let bends: Vec<f64> = voices
+            .controls()
+            .iter()
+            .filter(|voice| voice.control.value() > 0.0)
+            .map(|voice| voice.pitch_bend.value())
+            .collect();
+        // the member channels bend by up to 48 semitones, so the first note is bent up by 12
+        assert_eq!(bends.len(), 2);
+        assert!(bends.iter().any(|&bend| (bend - 2.0).abs() < 1.0e-9));
+        assert!(bends.contains(&1.0));
+    }
+
+    #[test]
+    fn mpe_configuration_in_a_file_sets_up_the_zone() {
+        // the member channels of the zone bend by up to 48 semitones, where the bend of a plain
+        // channel would reach only 2
+        let [rpn_coarse, rpn_fine, members] = &MPE_LOWER_ZONE;
+        let voices = play_file(&[
+            rpn_coarse,
+            rpn_fine,
+            members,
+            &[0x91, 64, 100],
+            &[0xE1, 0x00, 0x50],
+        ]);
+        assert!(voices
+            .controls()
+            .iter()
+            .any(|voice| (voice.pitch_bend.value() - 2.0).abs() < 1.0e-9));
+    }
+
+    #[test]
+    fn turning_a_zone_off_restores_the_bend_range() {
+        // a zone over every channel, then none, so channel 2 bends by 2 semitones again
+        let [rpn_coarse, rpn_fine, members] = &MPE_LOWER_ZONE;
+        let voices = play(&[
+            rpn_coarse,
+            rpn_fine,
+            members,
+            rpn_coarse,
+            rpn_fine,
+            &[0xB0, 6, 0],
+            &[0x91, 64, 100],
+            &[0xE1, 0x00, 0x50],
+        ]);
+        let bend = 2.0_f64.powf(2.0 / 12.0 * 0.25);
+        assert!(voices
+            .controls()
+            .iter()
+            .any(|voice| (voice.pitch_bend.value() - bend).abs() < 1.0e-9));
+    }
+
+    #[test]
+    fn mpe_unisons_are_released_on_their_own_channels() {
+        let [rpn_coarse, rpn_fine, members] = &MPE_LOWER_ZONE;
+        let voices = play(&[
+            rpn_coarse,
+            rpn_fine,
+            members,
+            &[0x91, 64, 100],
+            &[0x92, 64, 100],
+            &[0x81, 64, 0],
+        ]);
+        assert_eq!(sounding(&voices), 1);
+    }
+}
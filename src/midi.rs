@@ -0,0 +1,178 @@
+//! MIDI input port selection and connection.
+//!
+//! Ports can be chosen by index, by a substring of their name, or all at once, and a virtual
+//! input port can be created for other applications (DAWs, sequencers) to connect to. Every
+//! connection feeds the same message callback.
+
+use crate::error::Error;
+use midir::{Ignore, MidiInput, MidiInputConnection};
+use std::sync::{Arc, Mutex};
+
+// Client name announced to the MIDI system for every connection.
+static CLIENT_NAME: &str = "twang";
+
+/// Which of the available MIDI input ports to connect to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PortSelection {
+    /// The port at this index in the list printed by `list_ports()`.
+    Index(usize),
+    /// The first port whose name contains this text (case-insensitive).
+    Name(String),
+    /// Every available port.
+    All,
+    /// No hardware port, only the virtual port (if any).
+    None,
+}
+
+impl PortSelection {
+    /// Parse a port selection: `all`, `none`, a port index, or part of a port name.
+    pub fn parse(spec: &str) -> Self {
+        match spec {
+            "all" => PortSelection::All,
+            "none" => PortSelection::None,
+            _ => match spec.parse() {
+                Ok(index) => PortSelection::Index(index),
+                Err(_) => PortSelection::Name(spec.to_string()),
+            },
+        }
+    }
+}
+
+/// Names of the available MIDI input ports, in index order.
+pub fn list_ports() -> Result<Vec<String>, Error> {
+    let midi_in = MidiInput::new(CLIENT_NAME)?;
+    Ok(midi_in
+        .ports()
+        .iter()
+        .map(|port| {
+            midi_in
+                .port_name(port)
+                .unwrap_or_else(|_| "(unknown)".into())
+        })
+        .collect())
+}
+
+/// Connect to the selected ports, and create a virtual input port named `virtual_port` if given,
+/// passing every message received on any of them to `callback`. The connections stay open until
+/// the returned handles are dropped.
+pub fn connect_inputs<F>(
+    selection: &PortSelection,
+    virtual_port: Option<&str>,
+    callback: F,
+) -> Result<Vec<MidiInputConnection<()>>, Error>
+where
+    F: FnMut(&[u8]) + Send + 'static,
+{
+    let callback = Arc::new(Mutex::new(callback));
+    let receive = || {
+        let callback = Arc::clone(&callback);
+        move |_stamp: u64, message: &[u8], _: &mut ()| {
+            if let Ok(mut callback) = callback.lock() {
+                callback(message)
+            }
+        }
+    };
+
+    let names = list_ports()?;
+    let indices: Vec<usize> = match selection {
+        PortSelection::Index(index) => {
+            if *index >= names.len() {
+                return Err(Error::NoMidiPort {
+                    index: *index,
+                    count: names.len(),
+                });
+            }
+            vec![*index]
+        }
+        PortSelection::Name(name) => {
+            let pattern = name.to_lowercase();
+            match names
+                .iter()
+                .position(|n| n.to_lowercase().contains(&pattern))
+            {
+                Some(index) => vec![index],
+                None => {
+                    return Err(Error::NoMatchingMidiPort {
+                        name: name.clone(),
+                        available: names,
+                    })
+                }
+            }
+        }
+        PortSelection::All => (0..names.len()).collect(),
+        PortSelection::None => vec![],
+    };
+
+    let mut connections = vec![];
+    for index in indices {
+        // each connection consumes its own `MidiInput`
+        let mut midi_in = MidiInput::new(CLIENT_NAME)?;
+        midi_in.ignore(Ignore::None);
+        let Some(port) = midi_in.ports().get(index).cloned() else {
+            return Err(Error::NoMidiPort {
+                index,
+                count: midi_in.port_count(),
+            });
+        };
+        let connection = midi_in
+            .connect(&port, "twang-input", receive(), ())
+            .map_err(|err| Error::MidiConnect {
+                port: names[index].clone(),
+                kind: err.kind(),
+            })?;
+        println!("Connected to MIDI input {index}: '{}'", names[index]);
+        connections.push(connection);
+    }
+
+    if let Some(name) = virtual_port {
+        connections.push(create_virtual(name, receive())?);
+        println!("Created virtual MIDI input '{name}'");
+    }
+
+    if connections.is_empty() {
+        return Err(Error::NoMidiInput);
+    }
+    Ok(connections)
+}
+
+#[cfg(unix)]
+fn create_virtual<F>(name: &str, receive: F) -> Result<MidiInputConnection<()>, Error>
+where
+    F: FnMut(u64, &[u8], &mut ()) + Send + 'static,
+{
+    use midir::os::unix::VirtualInput;
+    let mut midi_in = MidiInput::new(CLIENT_NAME)?;
+    midi_in.ignore(Ignore::None);
+    midi_in
+        .create_virtual(name, receive, ())
+        .map_err(|err| Error::MidiConnect {
+            port: name.to_string(),
+            kind: err.kind(),
+        })
+}
+
+#[cfg(not(unix))]
+fn create_virtual<F>(name: &str, _receive: F) -> Result<MidiInputConnection<()>, Error>
+where
+    F: FnMut(u64, &[u8], &mut ()) + Send + 'static,
+{
+    Err(Error::VirtualPortUnsupported(name.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn port_selections_are_parsed() {
+        assert_eq!(PortSelection::parse("all"), PortSelection::All);
+        assert_eq!(PortSelection::parse("none"), PortSelection::None);
+        assert_eq!(PortSelection::parse("2"), PortSelection::Index(2));
+        assert_eq!(
+            PortSelection::parse("Keystation"),
+            PortSelection::Name("Keystation".into())
+        );
+        // a negative number can't be an index, so it is part of a name
+        assert_eq!(PortSelection::parse("-1"), PortSelection::Name("-1".into()));
+    }
+}
@@ -0,0 +1,146 @@
+//! MIDI Polyphonic Expression (MPE) zones.
+//!
+//! An MPE controller plays every note on a channel of its own, so the pitch bend, pressure and
+//! timbre (CC 74) of that channel belong to the one note, and a string can be slid or shaken on its
+//! own. The channels are split into up to two zones: the lower zone is managed from channel 1 and
+//! plays its notes on channels 2 upwards, and the upper zone is managed from channel 16 and plays
+//! on channels 15 downwards. Messages on a manager channel apply to every note of its zone.
+//!
+//! A controller sets up each zone with an MPE Configuration Message (Registered Parameter Number
+//! 6 on the manager channel, with the number of member channels as its value), and `--mpe` sets up
+//! a lower zone over every channel for controllers that don't send one. Outside the zones, every
+//! channel is played as usual.
+
+use std::ops::{Range, RangeInclusive};
+
+/// Bend range of the member channels of a zone once it is configured (semitones), as the MPE
+/// specification requires.
+pub static MPE_BEND_RANGE: f64 = 48.0;
+
+/// Manager channel of the lower zone (0 to 15).
+pub static LOWER_MANAGER: usize = 0;
+
+// Manager channel of the upper zone.
+static UPPER_MANAGER: usize = 15;
+
+/// The MPE zones, as the number of member channels of each (0 when the zone is off).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Zones {
+    lower: usize,
+    upper: usize,
+}
+
+impl Zones {
+    /// No zones, with every channel played as usual.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Give the zone managed from `manager` `members` member channels, or turn it off with 0,
+    /// shrinking the other zone if they would overlap. Returns the member channels of the zone,
+    /// and the channels that have left the lower and the upper zone, or `None` if `manager`
+    /// doesn't manage a zone.
+    pub fn configure(
+        &mut self,
+        manager: usize,
+        members: u8,
+    ) -> Option<(RangeInclusive<usize>, [Range<usize>; 2])> {
+        // both managers and every member channel must fit in the 16 channels
+        let before = *self;
+        let members = (members as usize).min(15);
+        let room = 14_usize.saturating_sub(members);
+        if manager == LOWER_MANAGER {
+            self.lower = members;
+            self.upper = self.upper.min(room);
+        } else if manager == UPPER_MANAGER {
+            self.upper = members;
+            self.lower = self.lower.min(room);
+        }
+        // a zone only loses the channels furthest from its manager, and the ranges are empty for
+        // a zone that hasn't shrunk
+        let left = [
+            LOWER_MANAGER + 1 + self.lower..LOWER_MANAGER + 1 + before.lower,
+            UPPER_MANAGER - before.upper..UPPER_MANAGER - self.upper,
+        ];
+        self.members(manager).map(|members| (members, left))
+    }
+
+    /// The manager of the zone `channel` is a member channel of, if any.
+    pub fn manager(&self, channel: usize) -> Option<usize> {
+        [LOWER_MANAGER, UPPER_MANAGER]
+            .into_iter()
+            .find(|&manager| self.members(manager).is_some_and(|m| m.contains(&channel)))
+    }
+
+    /// The channels a message on `channel` applies to: the whole zone on a manager channel, and
+    /// the channel alone otherwise.
+    pub fn controlled(&self, channel: usize) -> RangeInclusive<usize> {
+        match self.members(channel) {
+            Some(members) if !members.is_empty() => {
+                channel.min(*members.start())..=channel.max(*members.end())
+            }
+            _ => channel..=channel,
+        }
+    }
+
+    /// The member channels of the zone managed from `manager`, which are empty if the zone is
+    /// off, or `None` if `manager` doesn't manage a zone.
+    fn members(&self, manager: usize) -> Option<RangeInclusive<usize>> {
+        if manager == LOWER_MANAGER {
+            Some(LOWER_MANAGER + 1..=LOWER_MANAGER + self.lower)
+        } else if manager == UPPER_MANAGER {
+            Some(UPPER_MANAGER - self.upper..=UPPER_MANAGER - 1)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Configure the zone of `manager`, returning its member channels and the channels that left
+    // either zone.
+    fn configure(zones: &mut Zones, manager: usize, members: u8) -> (Vec<usize>, Vec<usize>) {
+        let (members, left) = zones.configure(manager, members).unwrap();
+        (members.collect(), left.into_iter().flatten().collect())
+    }
+
+    #[test]
+    fn zones_shrink_to_make_room_for_each_other() {
+        let mut zones = Zones::new();
+        // a lower zone over every channel takes the upper manager's channel too
+        let (members, left) = configure(&mut zones, LOWER_MANAGER, 15);
+        assert_eq!((members, left), ((1..=15).collect(), vec![]));
+        assert_eq!(zones.manager(15), Some(LOWER_MANAGER));
+
+        // an upper zone of 5 leaves the lower zone the 9 channels it doesn't need
+        let (members, left) = configure(&mut zones, UPPER_MANAGER, 5);
+        assert_eq!((members, left), ((10..=14).collect(), (10..=15).collect()));
+        assert_eq!(zones.manager(9), Some(LOWER_MANAGER));
+        assert_eq!(zones.manager(10), Some(UPPER_MANAGER));
+        assert_eq!(zones.manager(15), None);
+
+        // growing the lower zone back to 12 shrinks the upper zone to 2
+        let (members, left) = configure(&mut zones, LOWER_MANAGER, 12);
+        assert_eq!((members, left), ((1..=12).collect(), vec![10, 11, 12]));
+        assert_eq!(zones.controlled(UPPER_MANAGER), 13..=15);
+
+        // only the two managers can configure a zone
+        assert_eq!(zones.configure(7, 3), None);
+        assert_eq!(zones.controlled(LOWER_MANAGER), 0..=12);
+    }
+
+    #[test]
+    fn zones_turned_off_control_only_their_manager() {
+        let mut zones = Zones::new();
+        zones.configure(LOWER_MANAGER, 15);
+        let (members, left) = configure(&mut zones, LOWER_MANAGER, 0);
+        assert_eq!((members, left), (vec![], (1..=15).collect()));
+        assert_eq!(zones.controlled(LOWER_MANAGER), 0..=0);
+        assert_eq!(zones.controlled(UPPER_MANAGER), 15..=15);
+        assert_eq!(zones.controlled(3), 3..=3);
+        assert_eq!(zones.manager(3), None);
+    }
+}
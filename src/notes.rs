@@ -0,0 +1,193 @@
+//! Key and pedal state of every MIDI note.
+//!
+//! Whether a note should still be sounding depends on more than its own note-on and note-off: the
+//! sustain pedal keeps every released note sounding while it is down, and the sostenuto pedal keeps
+//! only the notes that were held when it went down. `NoteTracker` follows the keys and pedals and
+//! works out which notes are held, which are sustained and which are released, and the
+//! `VoiceAllocator` consults it to decide when to release each voice. It also keeps the soft pedal,
+//! which releases nothing but makes the notes started while it is down softer.
+
+/// What has become of a note.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum NoteState {
+    /// The key is up and no pedal holds the note, so it is left to die away.
+    #[default]
+    Released,
+    /// The key is down.
+    Held,
+    /// The key is up, but a pedal is keeping the note sounding.
+    Sustained,
+}
+
+/// Follows the keys and pedals of one MIDI channel.
+#[derive(Clone, Debug)]
+pub struct NoteTracker {
+    states: [NoteState; 128],
+    // notes held when the sostenuto pedal went down, which it keeps sounding until it comes up
+    latched: [bool; 128],
+    sustain: bool,
+    sostenuto: bool,
+    soft: bool,
+}
+
+impl NoteTracker {
+    /// A tracker with every note released and the pedals up.
+    pub fn new() -> Self {
+        Self {
+            states: [NoteState::Released; 128],
+            latched: [false; 128],
+            sustain: false,
+            sostenuto: false,
+            soft: false,
+        }
+    }
+
+    /// The state of `note`. Notes outside the MIDI range are always released.
+    pub fn state(&self, note: u8) -> NoteState {
+        self.states.get(note as usize).copied().unwrap_or_default()
+    }
+
+    /// The key of `note` went down. A note the sostenuto pedal holds stays held by it when it is
+    /// struck again.
+    pub fn note_on(&mut self, note: u8) {
+        if let Some(state) = self.states.get_mut(note as usize) {
+            *state = NoteState::Held;
+        }
+    }
+
+    /// The key of `note` came up: the note is sustained if a pedal holds it, and released
+    /// otherwise.
+    pub fn note_off(&mut self, note: u8) {
+        if self.state(note) != NoteState::Held {
+            return;
+        }
+        self.states[note as usize] = if self.sustain || self.latched[note as usize] {
+            NoteState::Sustained
+        } else {
+            NoteState::Released
+        };
+    }
+
+    /// Every key came up, with each note sustained or released as by `note_off()`.
+    pub fn all_notes_off(&mut self) {
+        for note in 0..128 {
+            self.note_off(note);
+        }
+    }
+
+    /// Release every note, whatever the pedals.
+    pub fn all_sound_off(&mut self) {
+        self.states = [NoteState::Released; 128];
+        self.latched = [false; 128];
+    }
+
+    /// The sustain pedal went down, or came up and released the notes it was sustaining.
+    pub fn sustain(&mut self, down: bool) {
+        self.sustain = down;
+        if !down {
+            self.release_unheld();
+        }
+    }
+
+    /// The sostenuto pedal went down and latched the notes held at that moment, or came up and
+    /// released the notes it was sustaining. Pressing it again while it is down changes nothing.
+    pub fn sostenuto(&mut self, down: bool) {
+        if down && !self.sostenuto {
+            for (latched, state) in self.latched.iter_mut().zip(&self.states) {
+                *latched = *state == NoteState::Held;
+            }
+        } else if !down {
+            self.latched = [false; 128];
+            self.release_unheld();
+        }
+        self.sostenuto = down;
+    }
+
+    /// The soft pedal went down, or came up.
+    pub fn soft(&mut self, down: bool) {
+        self.soft = down;
+    }
+
+    /// Whether the soft pedal is down.
+    pub fn is_soft(&self) -> bool {
+        self.soft
+    }
+
+    /// Release the sustained notes no pedal holds any more.
+    fn release_unheld(&mut self) {
+        for (state, latched) in self.states.iter_mut().zip(&self.latched) {
+            if *state == NoteState::Sustained && !self.sustain && !latched {
+                *state = NoteState::Released;
+            }
+        }
+    }
+}
+
+impl Default for NoteTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sustained_notes_wait_for_the_pedal() {
+        let mut notes = NoteTracker::new();
+        notes.note_on(60);
+        notes.sustain(true);
+        notes.note_on(64);
+        notes.note_off(60);
+        assert_eq!(notes.state(60), NoteState::Sustained);
+        assert_eq!(notes.state(64), NoteState::Held);
+
+        notes.sustain(false);
+        assert_eq!(notes.state(60), NoteState::Released);
+        assert_eq!(notes.state(64), NoteState::Held);
+        notes.note_off(64);
+        assert_eq!(notes.state(64), NoteState::Released);
+    }
+
+    #[test]
+    fn sostenuto_latches_only_the_notes_held_when_it_goes_down() {
+        let mut notes = NoteTracker::new();
+        notes.note_on(48);
+        notes.note_on(60);
+        notes.sostenuto(true);
+        notes.note_on(64);
+        // pressing the pedal again, or striking a latched note again, changes nothing
+        notes.sostenuto(true);
+        notes.note_off(60);
+        notes.note_on(60);
+        for note in [48, 60, 64] {
+            notes.note_off(note);
+        }
+        assert_eq!(notes.state(48), NoteState::Sustained);
+        assert_eq!(notes.state(60), NoteState::Sustained);
+        assert_eq!(notes.state(64), NoteState::Released);
+
+        notes.sostenuto(false);
+        assert_eq!(notes.state(48), NoteState::Released);
+        assert_eq!(notes.state(60), NoteState::Released);
+    }
+
+    #[test]
+    fn all_sound_off_releases_sustained_notes() {
+        let mut notes = NoteTracker::new();
+        notes.sustain(true);
+        notes.note_on(60);
+        notes.note_on(62);
+        notes.all_notes_off();
+        assert_eq!(notes.state(60), NoteState::Sustained);
+        assert_eq!(notes.state(62), NoteState::Sustained);
+
+        notes.all_sound_off();
+        assert_eq!(notes.state(60), NoteState::Released);
+        assert_eq!(notes.state(62), NoteState::Released);
+        // notes outside the MIDI range are ignored
+        notes.note_on(200);
+        assert_eq!(notes.state(200), NoteState::Released);
+    }
+}
@@ -0,0 +1,258 @@
+//! Magnetic pickups for electric-guitar tones.
+//!
+//! A pickup senses the string at one point along its length, so it cannot hear the partials that
+//! have a node there. In the waveguide loop, that point is reached directly and again after the
+//! wave has reflected off the bridge, inverted, which makes the pickup a comb filter whose delay is
+//! the time the wave takes to travel from the pickup to the bridge and back. That time only
+//! depends on the distance from the bridge, so it stays the same on every fret.
+//!
+//! A `bidirectional()` string can be tapped at the coils directly, and then only needs the pickup
+//! for the resonant lowpass formed by the coil's inductance and the capacitance of the cable,
+//! which gives each kind of pickup its peak and its roll-off.
+
+use crate::waveguide::linear;
+use fundsp::hacker::*;
+use std::f64::consts::PI;
+
+// Distance between the two coils of a humbucker, as a fraction of the string length.
+static HUMBUCKER_SPACING: f64 = 0.03;
+
+/// Where the pickup sits under the strings.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum PickupPosition {
+    /// Furthest from the bridge: a warm, round tone.
+    Neck,
+    /// Between the neck and bridge pickups.
+    Middle,
+    /// Closest to the bridge: a thin, bright tone with little of the fundamental.
+    Bridge,
+}
+
+impl PickupPosition {
+    /// Distance from the bridge as a fraction of the open string length, as on a 25.5" scale
+    /// guitar.
+    pub fn position(&self) -> f64 {
+        match self {
+            PickupPosition::Neck => 0.25,
+            PickupPosition::Middle => 0.16,
+            PickupPosition::Bridge => 0.07,
+        }
+    }
+}
+
+/// The construction of the pickup.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum PickupType {
+    /// A single coil: a bright, sharp resonance.
+    SingleCoil,
+    /// Two coils side by side, summed. Hears the string at two points, and its higher
+    /// inductance puts the resonance lower, for a thicker tone.
+    Humbucker,
+}
+
+impl PickupType {
+    /// Distance of each coil from the bridge, as a fraction of the open string length, and how
+    /// many coils there are. A single coil leaves the second position unused.
+    pub fn coils(&self, position: PickupPosition) -> ([f64; 2], usize) {
+        let first = position.position();
+        match self {
+            PickupType::SingleCoil => ([first, first], 1),
+            PickupType::Humbucker => ([first, first + HUMBUCKER_SPACING], 2),
+        }
+    }
+
+    /// Frequency (Hz) and Q of the resonant lowpass formed by the coil and the cable.
+    fn resonance(&self) -> (f64, f64) {
+        match self {
+            PickupType::SingleCoil => (4500.0, 2.0),
+            PickupType::Humbucker => (2800.0, 1.5),
+        }
+    }
+}
+
+/// Reads the string like a pickup: a comb filter for each coil, then a resonant lowpass.
+#[derive(Clone)]
+pub struct Pickup {
+    kind: PickupType,
+    // delay (s) of the reflection seen by each coil; a single coil leaves the second unused, and a
+    // string that has already been tapped at the coils needs none
+    delays: [f64; 2],
+    coils: usize,
+    sample_rate: f64,
+    buffer: Vec<f64>,
+    mask: usize,
+    write: usize,
+    // coefficients and state of the lowpass biquad
+    b: [f64; 3],
+    a: [f64; 2],
+    x1: f64,
+    x2: f64,
+    y1: f64,
+    y2: f64,
+}
+
+impl Pickup {
+    /// Read the buffer `delay` samples before the newest sample, interpolating linearly.
+    #[inline]
+    fn read(&self, delay: f64) -> f64 {
+        linear(delay, |i| {
+            self.buffer[(self.write.wrapping_sub(i)) & self.mask]
+        })
+    }
+}
+
+impl AudioNode for Pickup {
+    const ID: u64 = 1006;
+    type Sample = f64;
+    type Inputs = U1;
+    type Outputs = U1;
+    type Setting = ();
+
+    fn reset(&mut self, sample_rate: Option<f64>) {
+        if let Some(sample_rate) = sample_rate {
+            self.sample_rate = sample_rate;
+            let longest = self.delays.iter().fold(0.0, |a: f64, b| a.max(*b));
+            let length = (longest * sample_rate).ceil() as usize + 2;
+            self.buffer = vec![0.0; length.next_power_of_two()];
+            self.mask = self.buffer.len() - 1;
+
+            // lowpass from the Audio EQ Cookbook
+            let (freq, q) = self.kind.resonance();
+            let omega = 2.0 * PI * min(freq, 0.45 * sample_rate) / sample_rate;
+            let alpha = omega.sin() / (2.0 * q);
+            let a0 = 1.0 + alpha;
+            let b1 = (1.0 - omega.cos()) / a0;
+            self.b = [0.5 * b1, b1, 0.5 * b1];
+            self.a = [-2.0 * omega.cos() / a0, (1.0 - alpha) / a0];
+        }
+        self.buffer.fill(0.0);
+        self.write = 0;
+        self.x1 = 0.0;
+        self.x2 = 0.0;
+        self.y1 = 0.0;
+        self.y2 = 0.0;
+    }
+
+    #[inline]
+    fn tick(&mut self, input: &Frame<f64, U1>) -> Frame<f64, U1> {
+        self.write = (self.write + 1) & self.mask;
+        self.buffer[self.write] = input[0];
+        let x = if self.coils == 0 {
+            input[0]
+        } else {
+            let mut sensed = 0.0;
+            for delay in &self.delays[..self.coils] {
+                sensed += input[0] - self.read(delay * self.sample_rate);
+            }
+            sensed / self.coils as f64
+        };
+        let y = self.b[0] * x + self.b[1] * self.x1 + self.b[2] * self.x2
+            - self.a[0] * self.y1
+            - self.a[1] * self.y2;
+        self.x2 = self.x1;
+        self.x1 = x;
+        self.y2 = self.y1;
+        self.y1 = y;
+        [y].into()
+    }
+}
+
+/// A pickup of type `kind` at `position` under a string whose open round trip takes
+/// `round_trip` seconds. Without a `round_trip`, the string has already been tapped at the coils
+/// and only the resonant lowpass is applied.
+/// - Input 0: string
+/// - Output 0: pickup signal
+pub fn pickup(position: PickupPosition, kind: PickupType, round_trip: Option<f64>) -> An<Pickup> {
+    let (delays, coils) = match round_trip {
+        Some(round_trip) => {
+            let (positions, coils) = kind.coils(position);
+            (positions.map(|p| p * round_trip), coils)
+        }
+        None => ([0.0; 2], 0),
+    };
+    let mut node = Pickup {
+        kind,
+        delays,
+        coils,
+        sample_rate: DEFAULT_SR,
+        buffer: vec![],
+        mask: 0,
+        write: 0,
+        b: [0.0; 3],
+        a: [0.0; 2],
+        x1: 0.0,
+        x2: 0.0,
+        y1: 0.0,
+        y2: 0.0,
+    };
+    node.reset(Some(DEFAULT_SR));
+    An(node)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Peak output of `pickup` once it has settled on a sine of `freq` Hz, at 44.1 kHz.
+    fn gain(mut pickup: An<Pickup>, freq: f64) -> f64 {
+        let sample_rate = 44100.0;
+        pickup.reset(Some(sample_rate));
+        let settled: Vec<f64> = (0..8000)
+            .map(|i| pickup.filter_mono((TAU * freq * i as f64 / sample_rate).sin()))
+            .skip(4000)
+            .collect();
+        crate::peak(&settled)
+    }
+
+    #[test]
+    fn each_position_notches_the_harmonic_with_a_node_there() {
+        // a round trip of 1000 samples puts the notches on whole harmonics of 44.1 Hz: the 4th at
+        // a quarter of the way from the bridge, the 25th at 0.16 and the 100th at 0.07
+        let round_trip = Some(1000.0 / 44100.0);
+        for (position, harmonic) in [
+            (PickupPosition::Neck, 4.0),
+            (PickupPosition::Middle, 25.0),
+            (PickupPosition::Bridge, 100.0),
+        ] {
+            let single_coil = || pickup(position, PickupType::SingleCoil, round_trip);
+            let notch = 44.1 * harmonic;
+            // halfway between two notches the reflection adds up with the direct wave instead
+            let peak = notch * (1.0 + 0.5 / (harmonic * position.position()));
+            let (notched, passed) = (gain(single_coil(), notch), gain(single_coil(), peak));
+            assert!(
+                notched < 1e-6 * passed,
+                "{position:?}: {notched} against {passed}"
+            );
+        }
+        // the second coil of a neck humbucker hears the 4th harmonic where it still moves, so the
+        // notch of the single coil is filled in
+        let neck = |kind| pickup(PickupPosition::Neck, kind, round_trip);
+        let single_coil = gain(neck(PickupType::SingleCoil), 176.4);
+        let humbucker = gain(neck(PickupType::Humbucker), 176.4);
+        assert!(humbucker > 0.1, "{humbucker}");
+        assert!(
+            humbucker > 1e4 * single_coil,
+            "{humbucker} against {single_coil}"
+        );
+    }
+
+    #[test]
+    fn humbuckers_resonate_lower_than_single_coils() {
+        let resonance = |kind| {
+            (5..80)
+                .map(|i| 100.0 * i as f64)
+                .max_by(|a, b| {
+                    let gain = |freq| gain(pickup(PickupPosition::Neck, kind, None), freq);
+                    gain(*a).total_cmp(&gain(*b))
+                })
+                .unwrap()
+        };
+        let single_coil = resonance(PickupType::SingleCoil);
+        let humbucker = resonance(PickupType::Humbucker);
+        assert!((single_coil - 4500.0).abs() <= 500.0, "{single_coil}");
+        assert!((humbucker - 2800.0).abs() <= 500.0, "{humbucker}");
+        // and roll off the treble sooner
+        let treble = |kind| gain(pickup(PickupPosition::Neck, kind, None), 7000.0);
+        assert!(treble(PickupType::Humbucker) < 0.5 * treble(PickupType::SingleCoil));
+    }
+}
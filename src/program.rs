@@ -0,0 +1,162 @@
+//! Switching instruments on a MIDI program change.
+//!
+//! Every string of the synth is built for its own `StringModel`, so playing another instrument
+//! means building another synth. The new synth is built away from the audio thread and handed
+//! through a `SynthSlot` to the `ProgramSwitch` that plays the synth, which crossfades to it so the
+//! notes still ringing on the old instrument don't click. Once the old synth has faded out, it is
+//! handed back through the slot to be dropped away from the audio thread too.
+
+use fundsp::hacker::*;
+use fundsp::prelude::AudioUnit64;
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+
+// Length of the crossfade from the old instrument to the new one (s).
+static CROSSFADE_TIME: f64 = 0.02;
+
+// How many faded-out synths can wait to be dropped. Sending one never allocates, as the space is
+// set aside when the switch is created.
+static RETIRED_CAPACITY: usize = 4;
+
+/// Where a new synth waits for the `ProgramSwitch` to pick it up, and the synths it has faded out
+/// wait to be dropped.
+#[derive(Clone)]
+pub struct SynthSlot {
+    next: Arc<Mutex<Option<Box<dyn AudioUnit64>>>>,
+    retired: Arc<Mutex<Receiver<Box<dyn AudioUnit64>>>>,
+}
+
+impl SynthSlot {
+    /// Hand `synth` to the `ProgramSwitch` to crossfade to. It must already be reset to the sample
+    /// rate it is played at.
+    pub fn put(&self, synth: Box<dyn AudioUnit64>) {
+        self.collect();
+        if let Ok(mut next) = self.next.lock() {
+            *next = Some(synth);
+        }
+    }
+
+    /// Drop the synths the `ProgramSwitch` has faded out.
+    pub fn collect(&self) {
+        if let Ok(retired) = self.retired.lock() {
+            while retired.try_recv().is_ok() {}
+        }
+    }
+}
+
+/// Plays one synth, and crossfades to the next whenever one is put in its slot.
+#[derive(Clone)]
+pub struct ProgramSwitch {
+    current: Box<dyn AudioUnit64>,
+    previous: Option<Box<dyn AudioUnit64>>,
+    next: Arc<Mutex<Option<Box<dyn AudioUnit64>>>>,
+    retired: SyncSender<Box<dyn AudioUnit64>>,
+    // how far the crossfade has come (0 to 1), and how far it moves each sample
+    fade: f64,
+    step: f64,
+}
+
+impl ProgramSwitch {
+    /// Hand `synth` back to be dropped away from the audio thread. Only if nothing has collected
+    /// the synths faded out before it is it dropped here.
+    fn retire(&self, synth: Box<dyn AudioUnit64>) {
+        match self.retired.try_send(synth) {
+            Ok(()) => {}
+            Err(TrySendError::Full(synth) | TrySendError::Disconnected(synth)) => drop(synth),
+        }
+    }
+}
+
+impl AudioNode for ProgramSwitch {
+    const ID: u64 = 1018;
+    type Sample = f64;
+    type Inputs = U0;
+    type Outputs = U1;
+    type Setting = ();
+
+    fn reset(&mut self, sample_rate: Option<f64>) {
+        if let Some(sample_rate) = sample_rate {
+            self.step = 1.0 / (CROSSFADE_TIME * sample_rate);
+        }
+        self.current.reset(sample_rate);
+        if let Some(previous) = self.previous.take() {
+            self.retire(previous);
+        }
+        self.fade = 1.0;
+    }
+
+    #[inline]
+    fn tick(&mut self, _input: &Frame<f64, U0>) -> Frame<f64, U1> {
+        // a synth being handed over is picked up on a later sample
+        if let Ok(mut slot) = self.next.try_lock() {
+            if let Some(next) = slot.take() {
+                let previous = std::mem::replace(&mut self.current, next);
+                // a synth still fading out is cut off by the next switch
+                if let Some(previous) = self.previous.replace(previous) {
+                    self.retire(previous);
+                }
+                self.fade = 0.0;
+            }
+        }
+
+        let mut output = self.current.get_mono();
+        if let Some(previous) = &mut self.previous {
+            self.fade = min(self.fade + self.step, 1.0);
+            output = output * self.fade + previous.get_mono() * (1.0 - self.fade);
+            if self.fade >= 1.0 {
+                if let Some(previous) = self.previous.take() {
+                    self.retire(previous);
+                }
+            }
+        }
+        [output].into()
+    }
+}
+
+/// Play `synth`, until another synth is put in the returned slot.
+/// - Output 0: synth
+pub fn program_switch(synth: Box<dyn AudioUnit64>) -> (An<ProgramSwitch>, SynthSlot) {
+    let (retired, collected) = mpsc::sync_channel(RETIRED_CAPACITY);
+    let slot = SynthSlot {
+        next: Arc::new(Mutex::new(None)),
+        retired: Arc::new(Mutex::new(collected)),
+    };
+    let mut node = ProgramSwitch {
+        current: synth,
+        previous: None,
+        next: slot.next.clone(),
+        retired,
+        fade: 1.0,
+        step: 0.0,
+    };
+    node.reset(Some(DEFAULT_SR));
+    (An(node), slot)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn faded_out_synths_are_handed_back() {
+        let (mut switch, slot) = program_switch(Box::new(dc(1.0)));
+        slot.put(Box::new(dc(2.0)));
+        let samples: Vec<f64> = (0..(CROSSFADE_TIME * DEFAULT_SR) as usize + 2)
+            .map(|_| switch.get_mono())
+            .collect();
+        // the crossfade starts from the old synth, and rises steadily to the new one
+        assert!(samples[0] < 1.01);
+        assert!(samples.windows(2).all(|pair| pair[0] <= pair[1]));
+        assert_eq!(samples.last(), Some(&2.0));
+
+        // the old synth waits in the slot to be dropped, and nothing else does
+        let retired = slot.retired.lock().unwrap();
+        assert_eq!(
+            retired
+                .try_iter()
+                .map(|mut synth| synth.get_mono())
+                .collect::<Vec<_>>(),
+            [1.0]
+        );
+    }
+}
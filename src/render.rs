@@ -0,0 +1,304 @@
+//! Offline rendering to WAV files.
+//!
+//! The renderer drives the same synth graph and MIDI handler as live playback, but pulls samples
+//! itself instead of waiting on an audio device, so it runs on headless machines and faster than
+//! real time. Events are applied between samples, at the exact sample they are scheduled for.
+
+use crate::excitation::MAX_PLUCK_POSITION;
+use anyhow::{bail, Context};
+use fundsp::prelude::AudioUnit64;
+use hound::{SampleFormat, WavSpec, WavWriter};
+use midi_msg::{Channel, ChannelVoiceMsg, ControlChange, MidiMsg};
+use std::path::Path;
+use std::str::FromStr;
+
+/// Sample formats that can be written.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BitDepth {
+    Int16,
+    Int24,
+    Float32,
+}
+
+impl FromStr for BitDepth {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "16" => Ok(BitDepth::Int16),
+            "24" => Ok(BitDepth::Int24),
+            "32" => Ok(BitDepth::Float32),
+            _ => bail!("Unsupported bit depth '{s}', expected 16, 24 or 32"),
+        }
+    }
+}
+
+/// A MIDI message to apply at `time` seconds from the start of playback.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScheduledEvent {
+    pub time: f64,
+    pub msg: MidiMsg,
+}
+
+/// Steps through a list of events sorted by time, one sample at a time, so every event is
+/// handled at the exact sample it is scheduled for. Used both for offline rendering and for
+/// playing files live, where it runs inside the audio callback.
+pub struct EventPlayer {
+    events: Vec<ScheduledEvent>,
+    next: usize,
+    sample: u64,
+    sample_rate: f64,
+}
+
+impl EventPlayer {
+    pub fn new(events: Vec<ScheduledEvent>, sample_rate: f64) -> Self {
+        Self {
+            events,
+            next: 0,
+            sample: 0,
+            sample_rate,
+        }
+    }
+
+    /// Pass every event due at the current sample to `handle`, then move on to the next sample.
+    pub fn advance(&mut self, mut handle: impl FnMut(&MidiMsg)) {
+        let time = self.sample as f64 / self.sample_rate;
+        while let Some(event) = self.events.get(self.next).filter(|e| e.time <= time) {
+            handle(&event.msg);
+            self.next += 1;
+        }
+        self.sample += 1;
+    }
+}
+
+/// Time of the last event in `events`, which are sorted by time (s).
+pub fn duration(events: &[ScheduledEvent]) -> f64 {
+    events.last().map_or(0.0, |e| e.time)
+}
+
+/// Read a note list: one note per line as `<start (s)> <note> <velocity> <duration (s)>`, with
+/// blank lines and `#` comments ignored. A fifth column sets the pluck position of the note (as
+/// a fraction of the string length from the bridge), sent as CC 70 just before it starts.
+/// Returns the note-on and note-off events sorted by time.
+pub fn read_note_list<P: AsRef<Path>>(path: P) -> anyhow::Result<Vec<ScheduledEvent>> {
+    let path = path.as_ref();
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read note list {}", path.display()))?;
+
+    let mut events = vec![];
+    for (number, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let ([start, note, velocity, duration], position) = match fields[..] {
+            [start, note, velocity, duration] => ([start, note, velocity, duration], None),
+            [start, note, velocity, duration, position] => {
+                ([start, note, velocity, duration], Some(position))
+            }
+            _ => bail!(
+                "{}:{}: expected `<start> <note> <velocity> <duration> [<pluck position>]`",
+                path.display(),
+                number + 1
+            ),
+        };
+        let parse_error = || format!("{}:{}: invalid number", path.display(), number + 1);
+        let start: f64 = start.parse().with_context(parse_error)?;
+        let note: u8 = note.parse().with_context(parse_error)?;
+        let velocity: u8 = velocity.parse().with_context(parse_error)?;
+        let duration: f64 = duration.parse().with_context(parse_error)?;
+        let position: Option<f64> = position
+            .map(str::parse)
+            .transpose()
+            .with_context(parse_error)?;
+        let line_error = |what: &str| format!("{}:{}: {what}", path.display(), number + 1);
+        if note > 127 {
+            bail!(line_error(&format!("note {note} is outside 0 to 127")));
+        }
+        if velocity > 127 {
+            bail!(line_error(&format!(
+                "velocity {velocity} is outside 0 to 127"
+            )));
+        }
+        for (name, time) in [("start", start), ("duration", duration)] {
+            if !time.is_finite() || time < 0.0 {
+                bail!(line_error(&format!(
+                    "{name} {time} must be a finite number of seconds, from 0 up"
+                )));
+            }
+        }
+
+        let channel_voice = |msg| MidiMsg::ChannelVoice {
+            channel: Channel::Ch1,
+            msg,
+        };
+        // the sort below is stable, so the position stays just before the note it applies to
+        if let Some(position) = position {
+            let value = (127.0 * position / MAX_PLUCK_POSITION)
+                .round()
+                .clamp(0.0, 127.0);
+            events.push(ScheduledEvent {
+                time: start,
+                msg: channel_voice(ChannelVoiceMsg::ControlChange {
+                    control: ControlChange::SoundControl1(value as u8),
+                }),
+            });
+        }
+        events.push(ScheduledEvent {
+            time: start,
+            msg: channel_voice(ChannelVoiceMsg::NoteOn { note, velocity }),
+        });
+        events.push(ScheduledEvent {
+            time: start + duration,
+            msg: channel_voice(ChannelVoiceMsg::NoteOff { note, velocity: 0 }),
+        });
+    }
+    events.sort_by(|a, b| a.time.total_cmp(&b.time));
+    Ok(events)
+}
+
+/// Render `sound` to a stereo WAV file at `sample_rate`, passing each event to `handle` when its
+/// time comes. The render lasts until `tail` seconds after the last event, so notes can ring out.
+pub fn render_to_wav<P: AsRef<Path>>(
+    path: P,
+    events: Vec<ScheduledEvent>,
+    sound: &mut dyn AudioUnit64,
+    mut handle: impl FnMut(&MidiMsg),
+    sample_rate: u32,
+    bit_depth: BitDepth,
+    tail: f64,
+) -> anyhow::Result<()> {
+    let path = path.as_ref();
+    let (bits_per_sample, sample_format) = match bit_depth {
+        BitDepth::Int16 => (16, SampleFormat::Int),
+        BitDepth::Int24 => (24, SampleFormat::Int),
+        BitDepth::Float32 => (32, SampleFormat::Float),
+    };
+    let spec = WavSpec {
+        channels: 2,
+        sample_rate,
+        bits_per_sample,
+        sample_format,
+    };
+    let mut writer = WavWriter::create(path, spec)
+        .with_context(|| format!("Failed to create {}", path.display()))?;
+
+    sound.reset(Some(sample_rate as f64));
+    let length = ((duration(&events) + tail) * sample_rate as f64).ceil() as u64;
+    let mut player = EventPlayer::new(events, sample_rate as f64);
+    for _ in 0..length {
+        player.advance(&mut handle);
+        let (left, right) = sound.get_stereo();
+        for sample in [left, right] {
+            let sample = sample.clamp(-1.0, 1.0);
+            match bit_depth {
+                BitDepth::Int16 => writer.write_sample((sample * i16::MAX as f64) as i16)?,
+                BitDepth::Int24 => writer.write_sample((sample * 8_388_607.0) as i32)?,
+                BitDepth::Float32 => writer.write_sample(sample as f32)?,
+            }
+        }
+    }
+    writer.finalize()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A note list in the temporary directory, deleted when dropped.
+    struct NoteList(std::path::PathBuf);
+
+    impl Drop for NoteList {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    // Writes `text` to a note list in the temporary directory.
+    fn note_list(name: &str, text: &str) -> NoteList {
+        let path = std::env::temp_dir().join(format!("twang-{}-{name}.txt", std::process::id()));
+        std::fs::write(&path, text).unwrap();
+        NoteList(path)
+    }
+
+    #[test]
+    fn note_lists_are_read_in_time_order() {
+        let path = note_list(
+            "notes",
+            "# start note velocity duration\n\n0.5 52 90 1.0\n0.0 40 100 1.0 0.5 # plucked\n",
+        );
+        let events = read_note_list(&path.0).unwrap();
+        let channel_voice = |msg| MidiMsg::ChannelVoice {
+            channel: Channel::Ch1,
+            msg,
+        };
+        let expected = [
+            (
+                0.0,
+                ChannelVoiceMsg::ControlChange {
+                    control: ControlChange::SoundControl1(127),
+                },
+            ),
+            (
+                0.0,
+                ChannelVoiceMsg::NoteOn {
+                    note: 40,
+                    velocity: 100,
+                },
+            ),
+            (
+                0.5,
+                ChannelVoiceMsg::NoteOn {
+                    note: 52,
+                    velocity: 90,
+                },
+            ),
+            (
+                1.0,
+                ChannelVoiceMsg::NoteOff {
+                    note: 40,
+                    velocity: 0,
+                },
+            ),
+            (
+                1.5,
+                ChannelVoiceMsg::NoteOff {
+                    note: 52,
+                    velocity: 0,
+                },
+            ),
+        ]
+        .map(|(time, msg)| ScheduledEvent {
+            time,
+            msg: channel_voice(msg),
+        });
+        assert_eq!(events, expected);
+        assert_eq!(duration(&events), 1.5);
+    }
+
+    #[test]
+    fn malformed_note_lists_are_rejected() {
+        for (name, text, error) in [
+            ("columns", "0.0 40 100\n", ":1: expected"),
+            (
+                "number",
+                "0.0 40 100 1.0\n0.5 forty 100 1.0\n",
+                ":2: invalid number",
+            ),
+            ("range", "0.0 400 100 1.0\n", ":1: invalid number"),
+            ("position", "0.0 40 100 1.0 bridge\n", ":1: invalid number"),
+            ("note", "0.0 40 100 1.0\n0.5 128 100 1.0\n", ":2: note 128"),
+            ("velocity", "0.0 40 200 1.0\n", ":1: velocity 200"),
+            ("start", "-0.5 40 100 1.0\n", ":1: start -0.5"),
+            ("duration", "0.0 40 100 -1.0\n", ":1: duration -1"),
+            ("nan", "NaN 40 100 1.0\n", ":1: start NaN"),
+        ] {
+            let path = note_list(name, text);
+            let err = read_note_list(&path.0).unwrap_err().to_string();
+            assert!(err.contains(error), "{name}: {err}");
+        }
+        assert!(read_note_list("/nonexistent/notes.txt").is_err());
+    }
+}
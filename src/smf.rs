@@ -0,0 +1,240 @@
+//! Standard MIDI File (.mid) loading.
+//!
+//! Every track of the file is merged into a single list of `ScheduledEvent`s, with tick times
+//! converted to seconds through the file's tempo map. Each channel message is parsed on its own by
+//! `midi-msg`, exactly like messages arriving from a live MIDI port, so controllers that select and
+//! set parameters reach the channel one at a time whichever way they arrive.
+
+use crate::render::ScheduledEvent;
+use anyhow::{bail, Context};
+use midi_msg::MidiMsg;
+use midly::live::LiveEvent;
+use midly::{Format, MetaMessage, Smf, Timing, TrackEventKind};
+use std::path::Path;
+
+// Tempo assumed until the first tempo event, per the SMF spec: 120 bpm (us per beat).
+static DEFAULT_TEMPO: f64 = 500_000.0;
+
+/// Read a format 0 (single track) or format 1 (parallel tracks) MIDI file into a list of events
+/// sorted by time. Only channel voice and channel mode messages are kept, and messages that can't
+/// be parsed are skipped.
+pub fn read_midi_file<P: AsRef<Path>>(path: P) -> anyhow::Result<Vec<ScheduledEvent>> {
+    let path = path.as_ref();
+    let bytes =
+        std::fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let smf =
+        Smf::parse(&bytes).with_context(|| format!("Invalid MIDI file {}", path.display()))?;
+    if smf.header.format == Format::Sequential {
+        bail!("Format 2 (sequential) MIDI files are not supported");
+    }
+
+    // merge the tracks by absolute tick; the sort is stable, so simultaneous events keep their
+    // track order and a tempo change in the first track applies to notes at the same tick
+    let mut track_events = vec![];
+    for track in &smf.tracks {
+        let mut tick = 0_u64;
+        for event in track {
+            tick += event.delta.as_int() as u64;
+            track_events.push((tick, event.kind));
+        }
+    }
+    track_events.sort_by_key(|(tick, _)| *tick);
+
+    // seconds per tick, which for metrical timing depends on the current tempo
+    let seconds_per_tick = |tempo: f64| match smf.header.timing {
+        Timing::Metrical(ticks_per_beat) => tempo / 1_000_000.0 / ticks_per_beat.as_int() as f64,
+        Timing::Timecode(fps, subframes) => 1.0 / (fps.as_f32() as f64 * subframes as f64),
+    };
+
+    let mut events = vec![];
+    let mut tick_length = seconds_per_tick(DEFAULT_TEMPO);
+    let (mut time, mut last_tick) = (0.0, 0);
+    for (tick, kind) in track_events {
+        time += (tick - last_tick) as f64 * tick_length;
+        last_tick = tick;
+        match kind {
+            TrackEventKind::Meta(MetaMessage::Tempo(tempo)) => {
+                tick_length = seconds_per_tick(tempo.as_int() as f64);
+            }
+            TrackEventKind::Midi { channel, message } => {
+                let mut raw = vec![];
+                LiveEvent::Midi { channel, message }.write_std(&mut raw)?;
+                let Some(msg) = parse_message(&raw) else {
+                    continue;
+                };
+                if matches!(
+                    msg,
+                    MidiMsg::ChannelVoice { .. } | MidiMsg::ChannelMode { .. }
+                ) {
+                    events.push(ScheduledEvent { time, msg });
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(events)
+}
+
+// Parses a message of the file as `midi-msg` parses one from a live port. A message it can't parse
+// is skipped with a warning, as live input skips it, instead of rejecting the whole file.
+fn parse_message(raw: &[u8]) -> Option<MidiMsg> {
+    match MidiMsg::from_midi(raw) {
+        Ok((msg, _len)) => Some(msg),
+        Err(err) => {
+            eprintln!("Skipping MIDI message {raw:02X?}: {err}");
+            None
+        }
+    }
+}
+
+/// Save `recording`, a list of raw channel messages, as a single track MIDI file whose events all
+/// fall on the first tick.
+#[cfg(test)]
+pub fn save_recording<P: AsRef<Path>>(path: P, recording: &[&[u8]]) -> anyhow::Result<()> {
+    use midly::num::{u15, u28};
+    use midly::{Header, TrackEvent};
+
+    let mut track = vec![];
+    for bytes in recording {
+        let LiveEvent::Midi { channel, message } = LiveEvent::parse(bytes)? else {
+            bail!("Not a channel message: {bytes:?}");
+        };
+        track.push(TrackEvent {
+            delta: u28::new(0),
+            kind: TrackEventKind::Midi { channel, message },
+        });
+    }
+    track.push(TrackEvent {
+        delta: u28::new(0),
+        kind: TrackEventKind::Meta(MetaMessage::EndOfTrack),
+    });
+    let smf = Smf {
+        header: Header::new(Format::SingleTrack, Timing::Metrical(u15::new(480))),
+        tracks: vec![track],
+    };
+    smf.save(path.as_ref())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render::EventPlayer;
+    use midi_msg::ChannelVoiceMsg;
+    use midly::num::{u15, u24, u28, u4, u7};
+    use midly::{Header, MidiMessage, TrackEvent};
+
+    #[test]
+    fn tempo_changes_move_later_events() {
+        // a beat of 480 ticks lasts 0.5 s at first, then 0.25 s from the second beat on
+        let event = |delta: u32, kind| TrackEvent {
+            delta: u28::new(delta),
+            kind,
+        };
+        let tempo = |us: u32| TrackEventKind::Meta(MetaMessage::Tempo(u24::new(us)));
+        let note = |message| TrackEventKind::Midi {
+            channel: u4::new(0),
+            message,
+        };
+        let tempo_track = vec![
+            event(0, tempo(500_000)),
+            event(960, tempo(250_000)),
+            event(0, TrackEventKind::Meta(MetaMessage::EndOfTrack)),
+        ];
+        let note_track = vec![
+            event(
+                480,
+                note(MidiMessage::NoteOn {
+                    key: u7::new(40),
+                    vel: u7::new(100),
+                }),
+            ),
+            event(
+                960,
+                note(MidiMessage::NoteOff {
+                    key: u7::new(40),
+                    vel: u7::new(0),
+                }),
+            ),
+            event(0, TrackEventKind::Meta(MetaMessage::EndOfTrack)),
+        ];
+        let smf = Smf {
+            header: Header::new(Format::Parallel, Timing::Metrical(u15::new(480))),
+            tracks: vec![tempo_track, note_track],
+        };
+        let path = std::env::temp_dir().join(format!("twang-{}-tempo.mid", std::process::id()));
+        smf.save(&path).unwrap();
+
+        let events = read_midi_file(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let times: Vec<f64> = events.iter().map(|e| e.time).collect();
+        assert_eq!(times, [0.5, 1.25]);
+
+        // each event is handled on the sample it falls on
+        let sample_rate = 44100.0;
+        let mut player = EventPlayer::new(events, sample_rate);
+        let mut handled = vec![];
+        for sample in 0..60_000 {
+            player.advance(|msg| {
+                if let MidiMsg::ChannelVoice { msg, .. } = msg {
+                    handled.push((sample, *msg));
+                }
+            });
+        }
+        assert_eq!(
+            handled,
+            [
+                (
+                    22050,
+                    ChannelVoiceMsg::NoteOn {
+                        note: 40,
+                        velocity: 100
+                    }
+                ),
+                (
+                    55125,
+                    ChannelVoiceMsg::NoteOff {
+                        note: 40,
+                        velocity: 0
+                    }
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn controllers_are_parsed_like_live_input() {
+        // the selection and data entry of RPN 0 come out one controller at a time, as they do
+        // from a live port, instead of merged into a parameter
+        let recording: [&[u8]; 4] = [
+            &[0xB0, 101, 0],
+            &[0xB0, 100, 0],
+            &[0xB0, 6, 12],
+            &[0xB0, 38, 50],
+        ];
+        let path = std::env::temp_dir().join(format!("twang-{}-rpn.mid", std::process::id()));
+        save_recording(&path, &recording).unwrap();
+        let events = read_midi_file(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let from_file: Vec<MidiMsg> = events.into_iter().map(|e| e.msg).collect();
+        let live: Vec<MidiMsg> = recording
+            .iter()
+            .map(|bytes| MidiMsg::from_midi(bytes).unwrap().0)
+            .collect();
+        assert_eq!(from_file, live);
+    }
+
+    #[test]
+    fn unparsable_messages_are_skipped() {
+        // a note with a data byte out of range and a controller cut short are skipped, and a good
+        // note still comes through; midly masks the data bytes it reads from a file to 7 bits,
+        // so the bad messages are built by hand
+        assert_eq!(parse_message(&[0x90, 0x80, 100]), None);
+        assert_eq!(parse_message(&[0xB0, 7]), None);
+        assert_eq!(
+            parse_message(&[0x90, 40, 100]),
+            Some(MidiMsg::from_midi(&[0x90, 40, 100]).unwrap().0)
+        );
+    }
+}
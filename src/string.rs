@@ -0,0 +1,150 @@
+//! Physical model of a single string.
+//!
+//! A `StringModel` holds the physical properties that determine how a string sounds, and derives
+//! the quantities the waveguide in `create_sound()` needs: the wave velocity, the time a wave
+//! takes to travel up and back down the string, and the resulting fundamental.
+//!
+//! A real string is also stiff: it resists bending as well as being pulled straight by its
+//! tension, more so for the sharper bends of its higher partials, which therefore travel faster
+//! and sound sharp of the harmonic series. How stiff it is follows from the diameter and Young's
+//! modulus of its core.
+
+use std::f64::consts::PI;
+
+/// Young's modulus of steel (Pa).
+pub static STEEL_MODULUS: f64 = 200e9;
+
+/// Young's modulus of nylon (Pa).
+pub static NYLON_MODULUS: f64 = 5e9;
+
+/// Physical parameters of an ideal string with a small amount of loss and stiffness.
+/// The decay times are given in seconds and converted into a gain per round trip for whichever
+/// note is playing, so a fretted string rings as long as the open string.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StringModel {
+    /// Tension (N).
+    pub tension: f64,
+    /// Mass per unit length (kg/m).
+    pub linear_density: f64,
+    /// Vibrating (scale) length (m).
+    pub length: f64,
+    /// Time for the fundamental to die away by 60 dB (s), whatever note the string is playing.
+    pub decay_time: f64,
+    /// Time for partials at `HIGH_DECAY_FREQ` to die away by 60 dB (s). When set, this shapes
+    /// the loss filter instead of `brightness`.
+    pub high_decay_time: Option<f64>,
+    /// How much of the loop gain the highest partials keep on each round trip, relative to the
+    /// fundamental (0 to 1). Lower values make the string darker and its overtones die sooner.
+    pub brightness: f64,
+    /// Diameter of the core that gives the string its stiffness (m). The windings of a wound
+    /// string add mass but hardly any stiffness. 0 gives a perfectly flexible string.
+    pub diameter: f64,
+    /// Young's modulus of the core (Pa).
+    pub youngs_modulus: f64,
+}
+
+impl StringModel {
+    /// Create a string from its tension (N), linear density (kg/m) and length (m), with the
+    /// default decay time of 4 s, a brightness of 0.5 and no stiffness (a steel core of no
+    /// diameter).
+    pub fn new(tension: f64, linear_density: f64, length: f64) -> Self {
+        Self {
+            tension,
+            linear_density,
+            length,
+            decay_time: 4.0,
+            high_decay_time: None,
+            brightness: 0.5,
+            diameter: 0.0,
+            youngs_modulus: STEEL_MODULUS,
+        }
+    }
+
+    /// Create a string of the given linear density (kg/m) and length (m), with its tension
+    /// solved so that the open string sounds at `freq_hz`.
+    pub fn tuned(freq_hz: f64, linear_density: f64, length: f64) -> Self {
+        Self::new(
+            tension_for(freq_hz, linear_density, length),
+            linear_density,
+            length,
+        )
+    }
+
+    /// Speed of a transverse wave along the string (m/s): `sqrt(T / mu)`.
+    pub fn wave_velocity(&self) -> f64 {
+        (self.tension / self.linear_density).sqrt()
+    }
+
+    /// Time for a wave to travel to the end of the string and back (s). This is the length of the
+    /// waveguide loop.
+    pub fn round_trip(&self) -> f64 {
+        2.0 * self.length / self.wave_velocity()
+    }
+
+    /// Fundamental frequency of the open string (Hz).
+    pub fn fundamental(&self) -> f64 {
+        self.round_trip().recip()
+    }
+
+    /// Inharmonicity coefficient `B` of the open string: `pi^3 E d^4 / (64 T L^2)`. Partial `n`
+    /// of a stiff string sounds at `n * f * sqrt(1 + B n^2)`, where `f` is the frequency the string
+    /// would have without its stiffness.
+    pub fn stiffness(&self) -> f64 {
+        PI.powi(3) * self.youngs_modulus * self.diameter.powi(4)
+            / (64.0 * self.tension * self.length * self.length)
+    }
+
+    /// Inharmonicity coefficient of the string stopped to sound at `freq_hz`. Shortening the
+    /// string raises `B` with the square of its frequency.
+    pub fn stiffness_at(&self, freq_hz: f64) -> f64 {
+        let ratio = freq_hz / self.fundamental();
+        self.stiffness() * ratio * ratio
+    }
+
+    /// Frequency ratio of partial `n` to the fundamental when the string plays `freq_hz`,
+    /// stretched by the string's stiffness.
+    pub fn partial_ratio(&self, n: f64, freq_hz: f64) -> f64 {
+        let b = self.stiffness_at(freq_hz);
+        n * ((1.0 + b * n * n) / (1.0 + b)).sqrt()
+    }
+
+    /// Gain the fundamental needs on each round trip at `freq_hz` to ring for `decay_time`.
+    pub fn loop_gain(&self, freq_hz: f64) -> f64 {
+        decay_gain(freq_hz, self.decay_time)
+    }
+
+    /// Gain partials at `HIGH_DECAY_FREQ` need on each round trip at `freq_hz` to ring for
+    /// `high_decay_time`, if it is set.
+    pub fn high_loop_gain(&self, freq_hz: f64) -> Option<f64> {
+        self.high_decay_time
+            .map(|decay_time| decay_gain(freq_hz, decay_time))
+    }
+}
+
+/// Tension (N) at which a string of the given linear density (kg/m) and length (m) sounds at
+/// `freq_hz`. This is `f = sqrt(T / mu) / 2L` solved for `T`.
+pub fn tension_for(freq_hz: f64, linear_density: f64, length: f64) -> f64 {
+    let velocity = 2.0 * length * freq_hz;
+    linear_density * velocity * velocity
+}
+
+/// Gain per trip around a loop with fundamental `freq_hz` that makes the signal fall by 60 dB in
+/// `decay_time` seconds: `10^(-3 / (f * T60))`.
+fn decay_gain(freq_hz: f64, decay_time: f64) -> f64 {
+    10.0_f64.powf(-3.0 / (freq_hz * decay_time))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tuned_strings_sound_their_frequency() {
+        // from a low bass string to a high treble one
+        for (freq, density, length) in [(41.2, 0.0128, 0.864), (329.6, 0.0004, 0.648)] {
+            let string = StringModel::tuned(freq, density, length);
+            assert!((string.fundamental() - freq).abs() < 1e-9 * freq);
+            assert!((string.round_trip() - 1.0 / string.fundamental()).abs() < 1e-12);
+        }
+    }
+}
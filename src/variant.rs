@@ -0,0 +1,290 @@
+//! Variants of the Karplus-Strong algorithm.
+//!
+//! Karplus and Strong described more than the plucked string: flipping the sign of the loop at
+//! random turns the string into a drum, and Jaffe and Smith's decay stretching lets high notes
+//! ring on like a harp's. A string can also be kept sounding by a bow instead of being left to
+//! die away, which needs the waveguide split at the bow: the bow sticks to the string and drags
+//! it along until the string moves too fast relative to it, when it slips, and the waves it sends
+//! towards the nut and the bridge return to it after reflecting off each end. All of these reuse
+//! the loss filter, dispersion and interpolation of the plucked string.
+
+use crate::dispersion::{dispersion, Dispersion};
+use crate::excitation::noise;
+use crate::expression::{damper, Damper};
+use crate::loss::{loop_filter, LoopFilter, LossFilter};
+use crate::string::StringModel;
+use crate::waveguide::{filter_delay, lagrange};
+use fundsp::hacker::*;
+
+// Closest the bow may be to the bridge, and furthest from it, as a fraction of the string length.
+static MIN_BOW_POSITION: f64 = 0.02;
+static MAX_BOW_POSITION: f64 = 0.5;
+
+// Time for the bow to get up to speed when a note starts, and to come to rest when it ends (s).
+static BOW_ATTACK: f64 = 0.05;
+static BOW_RELEASE: f64 = 0.1;
+
+/// The algorithm each string is played with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Variant {
+    /// The plucked string.
+    Pluck,
+    /// Karplus and Strong's drum: the sign of the loop is flipped at random, so the tone loses its
+    /// pitch and decays into a snare-like rattle.
+    Drum,
+    /// A plucked string with Jaffe and Smith's decay-stretched loss filter, whose high partials
+    /// ring on like a harp's.
+    Harp,
+    /// A bowed string, sustained for as long as the note is held.
+    Bowed,
+}
+
+/// Flips the sign of the signal at random.
+#[derive(Clone)]
+pub struct SignFlip {
+    blend: f64,
+    seed: u64,
+}
+
+impl AudioNode for SignFlip {
+    const ID: u64 = 1014;
+    type Sample = f64;
+    type Inputs = U1;
+    type Outputs = U1;
+    type Setting = ();
+
+    fn reset(&mut self, _sample_rate: Option<f64>) {
+        self.seed = 1;
+    }
+
+    #[inline]
+    fn tick(&mut self, input: &Frame<f64, U1>) -> Frame<f64, U1> {
+        if self.blend >= 1.0 {
+            return [input[0]].into();
+        }
+        let chance = 0.5 * (noise(&mut self.seed) + 1.0);
+        if chance < self.blend {
+            [input[0]].into()
+        } else {
+            [-input[0]].into()
+        }
+    }
+}
+
+/// Keep the sign of each sample with probability `blend`, and flip it otherwise. A blend of 1
+/// passes the signal through, 0.5 gives the Karplus-Strong drum, and 0 flips every sample, which
+/// drops the string an octave and leaves only its odd harmonics.
+/// - Input 0: audio
+/// - Output 0: audio with its sign flipped at random
+pub fn sign_flip(blend: f64) -> An<SignFlip> {
+    An(SignFlip {
+        blend: clamp(0.0, 1.0, blend),
+        seed: 1,
+    })
+}
+
+/// A string split at the bow into two delay lines: one carrying the wave from the bow to the nut
+/// and back, the other from the bow to the bridge and back.
+#[derive(Clone)]
+pub struct Bow {
+    string: StringModel,
+    loss: LossFilter,
+    // slope of the friction curve, set by the bow pressure
+    slope: f64,
+    max_delay: f64,
+    sample_rate: f64,
+    bridge: LoopFilter,
+    stiffness: Dispersion,
+    damper: Damper,
+    neck_side: Vec<f64>,
+    bridge_side: Vec<f64>,
+    mask: usize,
+    write: usize,
+    // fundamental and bow position the lines are tuned to, and their lengths (samples)
+    freq: f64,
+    position: f64,
+    neck_length: f64,
+    bridge_length: f64,
+    // gate on the previous sample, and the speed the bow is heading for and moving at
+    gate: f64,
+    target: f64,
+    velocity: f64,
+    attack: f64,
+    release: f64,
+}
+
+impl Bow {
+    /// Retune the lines so a round trip, including the delay of the bridge filters, lasts one
+    /// period of `freq`, split at `position`.
+    fn tune(&mut self, freq: f64, position: f64) {
+        self.freq = freq;
+        self.position = position;
+        let filter_delay = filter_delay(&self.loss, &self.string, freq, self.sample_rate);
+        let length = self.sample_rate / freq - filter_delay - 2.0;
+        let longest = self.max_delay * self.sample_rate;
+        self.bridge_length = clamp(1.0, longest, position * length);
+        self.neck_length = clamp(1.0, longest, length - self.bridge_length);
+    }
+
+    /// Reflection coefficient of the bow for a difference `dv` between the speed of the bow and of
+    /// the string: 1 while the bow sticks, falling away as it slips.
+    #[inline]
+    fn friction(&self, dv: f64) -> f64 {
+        min((abs(dv * self.slope) + 0.75).powi(-4), 1.0)
+    }
+}
+
+impl AudioNode for Bow {
+    const ID: u64 = 1015;
+    type Sample = f64;
+    type Inputs = U5;
+    type Outputs = U1;
+    type Setting = ();
+
+    fn reset(&mut self, sample_rate: Option<f64>) {
+        if let Some(sample_rate) = sample_rate {
+            self.sample_rate = sample_rate;
+            let length = (self.max_delay * sample_rate).ceil() as usize + 8;
+            self.neck_side = vec![0.0; length.next_power_of_two()];
+            self.bridge_side = vec![0.0; length.next_power_of_two()];
+            self.mask = self.neck_side.len() - 1;
+            self.attack = 1.0 - (-1.0 / (BOW_ATTACK * sample_rate)).exp();
+            self.release = 1.0 - (-1.0 / (BOW_RELEASE * sample_rate)).exp();
+            // retune on the next sample
+            self.freq = 0.0;
+        }
+        self.bridge.reset(sample_rate);
+        self.stiffness.reset(sample_rate);
+        self.damper.reset(sample_rate);
+        self.neck_side.fill(0.0);
+        self.bridge_side.fill(0.0);
+        self.write = 0;
+        self.gate = 0.0;
+        self.target = 0.0;
+        self.velocity = 0.0;
+    }
+
+    #[inline]
+    fn tick(&mut self, input: &Frame<f64, U5>) -> Frame<f64, U1> {
+        let position = clamp(MIN_BOW_POSITION, MAX_BOW_POSITION, input[3]);
+        if input[1] != self.freq || position != self.position {
+            self.tune(input[1], position);
+        }
+
+        // the bow is drawn faster for louder notes, and lifted when the note ends
+        if input[0] > 0.0 && self.gate <= 0.0 {
+            self.target = 0.03 + 0.2 * clamp(0.0, 1.0, input[2]);
+        } else if input[0] <= 0.0 {
+            self.target = 0.0;
+        }
+        self.gate = input[0];
+        let rate = if self.target > self.velocity {
+            self.attack
+        } else {
+            self.release
+        };
+        self.velocity += (self.target - self.velocity) * rate;
+
+        // waves returning to the bow after reflecting, inverted, off the nut and the bridge
+        let at = |line: &[f64], i: usize| line[(self.write.wrapping_sub(i)) & self.mask];
+        let at_nut = lagrange::<4>(self.neck_length, 1.0, |i| at(&self.neck_side, i));
+        let at_bridge = lagrange::<4>(self.bridge_length, 1.0, |i| at(&self.bridge_side, i));
+        let lost = self.bridge.tick(&[at_bridge, self.freq].into())[0];
+        let dispersed = self.stiffness.tick(&[lost, self.freq].into())[0];
+        let from_bridge = -self.damper.tick(&[dispersed, self.freq, input[4]].into())[0];
+        let from_nut = -at_nut;
+
+        // the bow adds whatever it takes to drag the string along while it sticks
+        let dv = self.velocity - (from_nut + from_bridge);
+        let force = dv * self.friction(dv);
+        self.write = (self.write + 1) & self.mask;
+        self.neck_side[self.write] = from_bridge + force;
+        self.bridge_side[self.write] = from_nut + force;
+        [at_bridge].into()
+    }
+}
+
+/// A bowed string with the loss and dispersion of `string`, whose two delay lines are each up to
+/// `max_delay` seconds long. `pressure` (0 to 1) is how hard the bow is pressed into the string:
+/// light pressure gives a breathy, airy tone and heavy pressure a gritty one.
+/// - Input 0: gate, as set by `VoiceControls::control` and `retrigger()`
+/// - Input 1: fundamental (Hz)
+/// - Input 2: velocity (0 to 1), which sets the speed of the bow
+/// - Input 3: bow position, as a fraction of the string length from the bridge
+/// - Input 4: damping (0 to 1), from `VoiceControls::damping` and the release of the note
+/// - Output 0: string at the bridge
+pub fn bow(string: &StringModel, loss: LossFilter, pressure: f64, max_delay: f64) -> An<Bow> {
+    let mut node = Bow {
+        string: *string,
+        loss,
+        slope: 5.0 - 4.0 * clamp(0.0, 1.0, pressure),
+        max_delay,
+        sample_rate: DEFAULT_SR,
+        bridge: loop_filter(loss, string).0,
+        stiffness: dispersion(string).0,
+        damper: damper().0,
+        neck_side: vec![],
+        bridge_side: vec![],
+        mask: 0,
+        write: 0,
+        freq: 0.0,
+        position: 0.0,
+        neck_length: 1.0,
+        bridge_length: 1.0,
+        gate: 0.0,
+        target: 0.0,
+        velocity: 0.0,
+        attack: 0.0,
+        release: 0.0,
+    };
+    node.reset(Some(DEFAULT_SR));
+    An(node)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::peak;
+
+    #[test]
+    fn sign_flip_blends_between_keeping_and_flipping() {
+        let count_flips = |blend: f64| {
+            let mut node = sign_flip(blend);
+            (0..10000)
+                .filter(|_| node.tick(&[1.0].into())[0] < 0.0)
+                .count()
+        };
+        assert_eq!(count_flips(1.0), 0);
+        assert_eq!(count_flips(0.0), 10000);
+        let drum = count_flips(0.5);
+        assert!((4800..5200).contains(&drum), "flipped {drum} of 10000");
+    }
+
+    #[test]
+    fn bowed_string_sustains_until_released() {
+        let sample_rate = 44100.0;
+        let string = StringModel::tuned(220.0, 0.000477, 0.64);
+        for pressure in [0.0, 0.5, 1.0] {
+            let mut node = bow(&string, LossFilter::OnePole, pressure, 0.05);
+            node.reset(Some(sample_rate));
+            let mut play = |gate: f64, seconds: f64| -> Vec<f64> {
+                (0..(seconds * sample_rate) as usize)
+                    .map(|_| node.tick(&[gate, 220.0, 1.0, 0.1, 0.0].into())[0])
+                    .collect()
+            };
+            let held = play(1.0, 1.0);
+            let released = play(0.0, 2.0);
+            assert!(held.iter().chain(&released).all(|x| x.abs() < 2.0));
+            // the bow keeps the string sounding steadily for as long as the note is held, neither
+            // dying away nor building up, and the string dies away once the bow is lifted
+            let early = peak(&held[4410..8820]);
+            let late = peak(&held[39690..]);
+            assert!(early > 0.01, "bowed at {pressure}, the string is silent");
+            assert!(
+                late > 0.5 * early,
+                "bowed at {pressure}, the string dies away"
+            );
+            assert!(peak(&released[79380..]) < 0.01 * late);
+        }
+    }
+}
@@ -0,0 +1,801 @@
+//! Polyphonic voice pool.
+//!
+//! Every voice is a complete plucked string built by `create_sound()`, driven by its own set of
+//! `shared()` objects. The MIDI thread owns a `VoiceAllocator`, which decides which voice a new
+//! note is played on and writes that voice's controls. The audio thread owns the signal graphs,
+//! which are summed on a mix bus.
+//!
+//! When playing an `Instrument`, there is one voice per string. A string can only sound one note
+//! at a time, and only the notes its frets can reach.
+//!
+//! The allocator follows the keys and pedals of each channel with a `NoteTracker`, and only
+//! releases a voice once the tracker has released its note. A note released while the sustain
+//! pedal is down, or while the sostenuto pedal holds it, keeps sounding until the pedal comes up,
+//! and its string is kept from being taken by new notes until then.
+//!
+//! Every voice follows the bend, aftertouch and timbre of the channel its note was played on, and
+//! is plucked where that channel's pluck position says. In an MPE zone, each note has a channel of
+//! its own, so they are the note's alone, and the messages on the manager channel of the zone apply
+//! to all of its notes.
+
+use crate::channel::{
+    Change, ChannelState, DEFAULT_BEND_RANGE, DEFAULT_PLUCK_POSITION, DEFAULT_TIMBRE,
+};
+use crate::expression::Aftertouch;
+use crate::instrument::{program_preset, Instrument};
+use crate::mpe::{Zones, MPE_BEND_RANGE};
+use crate::notes::{NoteState, NoteTracker};
+use crate::string::StringModel;
+use fundsp::hacker::*;
+use fundsp::prelude::AudioUnit64;
+
+// Share of the velocity notes are played with while the soft pedal is down.
+static SOFT_PEDAL_GAIN: f64 = 0.6;
+
+/// The `shared()` objects that drive a single voice.
+/// * `pitch`, `volume`, `pitch_bend` and `control` are used as in the original monophonic synth.
+///   `pitch_bend` follows the bend of the channel that started the voice's note.
+///   `control` is set to 1.0 when the note starts and stays there while its key is held or a
+///   pedal sustains it, and goes to 0.0 when the note is released, which damps its string.
+/// * `trigger` is incremented on every note-on, so a voice can be re-plucked while its `control`
+///   is still held at 1.0.
+/// * `pluck_position` is where along the string the note is plucked, as a fraction of its length
+///   from the bridge.
+/// * `vibrato` and `damping` are set by aftertouch (0 to 1), as chosen by `--aftertouch`, and
+///   `damping` is also set to 1.0 to silence the voice on All Sound Off.
+/// * `timbre` (0 to 1) is set by CC 74, and turns the tone of the voice down as it falls.
+/// * `level` is written by the audio thread with the voice's current output level.
+/// * `bridge` is written by the audio thread with the voice's string, for the other strings to
+///   resonate with.
+#[derive(Clone)]
+pub struct VoiceControls {
+    pub pitch: Shared<f64>,
+    pub volume: Shared<f64>,
+    pub pitch_bend: Shared<f64>,
+    pub control: Shared<f64>,
+    pub trigger: Shared<f64>,
+    pub pluck_position: Shared<f64>,
+    pub vibrato: Shared<f64>,
+    pub damping: Shared<f64>,
+    pub timbre: Shared<f64>,
+    pub level: Shared<f64>,
+    pub bridge: Shared<f64>,
+}
+
+impl VoiceControls {
+    pub fn new() -> Self {
+        Self {
+            pitch: shared(0.0),
+            volume: shared(0.0),
+            pitch_bend: shared(1.0),
+            control: shared(0.0),
+            trigger: shared(0.0),
+            pluck_position: shared(DEFAULT_PLUCK_POSITION),
+            vibrato: shared(0.0),
+            damping: shared(0.0),
+            timbre: shared(DEFAULT_TIMBRE),
+            level: shared(0.0),
+            bridge: shared(0.0),
+        }
+    }
+}
+
+impl Default for VoiceControls {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// How a voice is chosen when a note-on arrives and every voice is busy.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum StealPolicy {
+    /// Take the voice whose note started the longest time ago.
+    Oldest,
+    /// Take the voice with the lowest output level.
+    Quietest,
+    /// Re-pluck the voice already playing the same note, otherwise take the oldest voice.
+    SameNote,
+}
+
+/// Bookkeeping for one voice on the MIDI thread. `sounding` is set from the note-on of its note
+/// until the voice is released, and `channel` is the MIDI channel (0 to 15) of the note.
+struct VoiceState {
+    controls: VoiceControls,
+    note: Option<u8>,
+    channel: usize,
+    sounding: bool,
+    started: u64,
+}
+
+impl VoiceState {
+    /// Start the release of the voice, once its note is neither held nor sustained, by setting its
+    /// `control` to 0.0, which damps the string until it is plucked again.
+    fn release(&mut self) {
+        self.sounding = false;
+        self.controls.control.set_value(0.0);
+    }
+}
+
+/// The controls of the voices of an instrument, with the string model each of them plays.
+pub type InstrumentVoices = (Vec<VoiceControls>, Vec<StringModel>);
+
+// An instrument a program change can switch to, with voices of its own set up in advance, so
+// switching to it doesn't allocate. While it plays, its voices are swapped with the ones the
+// allocator was created with.
+struct Program {
+    instrument: Instrument,
+    voices: Vec<VoiceState>,
+}
+
+/// Assigns incoming notes to voices.
+pub struct VoiceAllocator {
+    voices: Vec<VoiceState>,
+    instrument: Option<Instrument>,
+    programs: Vec<Program>,
+    // index of the program playing, if a program change has switched to one
+    program: Option<usize>,
+    policy: StealPolicy,
+    aftertouch: Aftertouch,
+    channels: [ChannelState; 16],
+    // bend range of the channels outside the MPE zones, until a controller changes it
+    bend_range: f64,
+    zones: Zones,
+    notes: [NoteTracker; 16],
+    clock: u64,
+}
+
+impl VoiceAllocator {
+    /// Create an allocator managing `voices` voices (at least one).
+    pub fn new(voices: usize, policy: StealPolicy) -> Self {
+        Self {
+            voices: (0..max(voices, 1))
+                .map(|_| VoiceState {
+                    controls: VoiceControls::new(),
+                    note: None,
+                    channel: 0,
+                    sounding: false,
+                    started: 0,
+                })
+                .collect(),
+            instrument: None,
+            programs: vec![],
+            program: None,
+            policy,
+            aftertouch: Aftertouch::Vibrato,
+            channels: [ChannelState::new(DEFAULT_BEND_RANGE); 16],
+            bend_range: DEFAULT_BEND_RANGE,
+            zones: Zones::new(),
+            notes: Default::default(),
+            clock: 0,
+        }
+    }
+
+    /// Create an allocator with one voice for each string of `instrument`.
+    pub fn for_instrument(instrument: Instrument, policy: StealPolicy) -> Self {
+        Self {
+            voices: Self::new(instrument.strings.len(), policy).voices,
+            instrument: Some(instrument),
+            ..Self::new(0, policy)
+        }
+    }
+
+    /// The same allocator, with `programs` to switch to on a program change.
+    pub fn with_programs(self, programs: Vec<Instrument>) -> Self {
+        let programs = programs
+            .into_iter()
+            .map(|instrument| Program {
+                voices: Self::new(instrument.strings.len(), self.policy).voices,
+                instrument,
+            })
+            .collect();
+        Self { programs, ..self }
+    }
+
+    /// The same allocator, with aftertouch applied to the voices as `aftertouch`.
+    pub fn with_aftertouch(self, aftertouch: Aftertouch) -> Self {
+        Self { aftertouch, ..self }
+    }
+
+    /// The same allocator, with every channel bending by up to `range` semitones until a
+    /// controller changes it.
+    pub fn with_bend_range(mut self, range: f64) -> Self {
+        for channel in &mut self.channels {
+            channel.set_bend_range(range);
+        }
+        Self {
+            bend_range: range,
+            ..self
+        }
+    }
+
+    /// The same allocator, with every channel plucking its notes at `position` (as a fraction of
+    /// the string length from the bridge) until a controller changes it.
+    pub fn with_pluck_position(mut self, position: f64) -> Self {
+        for channel in &mut self.channels {
+            channel.pluck_position = position;
+        }
+        self
+    }
+
+    /// Controls of every voice, in order, for building the signal graphs.
+    pub fn controls(&self) -> Vec<VoiceControls> {
+        self.voices.iter().map(|v| v.controls.clone()).collect()
+    }
+
+    /// Play `note` from MIDI `channel` on a free voice, stealing one if necessary. Returns the
+    /// chosen voice index, or `None` if no string of the instrument can reach the note.
+    pub fn note_on(&mut self, channel: usize, note: u8, velocity: u8) -> Option<usize> {
+        let (index, fret) = self.choose_voice(channel, note)?;
+        self.clock += 1;
+        self.notes[channel].note_on(note);
+        let voice = &mut self.voices[index];
+        voice.note = Some(note);
+        voice.channel = channel;
+        voice.sounding = true;
+        voice.started = self.clock;
+
+        // a fretted string sounds at the fundamental of its shortened length
+        let pitch = match self.instrument() {
+            Some(instrument) => instrument.fretted(index, fret).fundamental(),
+            None => midi_hz(note as f64),
+        };
+
+        // the soft pedal plays notes quieter, and so darker
+        let soft = if self.notes[channel].is_soft() {
+            SOFT_PEDAL_GAIN
+        } else {
+            1.0
+        };
+
+        let controls = &self.voices[index].controls;
+        self.press(controls, self.channels[channel].pressure);
+        controls.pitch.set_value(pitch);
+        controls.volume.set_value(soft * velocity as f64 / 127.0);
+        controls.pitch_bend.set_value(self.voice_bend(channel));
+        controls.timbre.set_value(self.channels[channel].timbre);
+        controls
+            .pluck_position
+            .set_value(self.channels[channel].pluck_position);
+        controls.trigger.set_value(controls.trigger.value() + 1.0);
+        controls.control.set_value(1.0);
+        Some(index)
+    }
+
+    /// Release every voice playing `note` from `channel`, unless a pedal is sustaining it.
+    pub fn note_off(&mut self, channel: usize, note: u8) {
+        self.notes[channel].note_off(note);
+        self.release_finished();
+    }
+
+    /// Release every voice of `channel` whose key is down, as if each key had come up.
+    pub fn all_notes_off(&mut self, channel: usize) {
+        for c in self.zones.controlled(channel) {
+            self.notes[c].all_notes_off();
+        }
+        self.release_finished();
+    }
+
+    /// Release every voice of `channel`, whatever the pedals, and damp every string so it falls
+    /// silent.
+    pub fn all_sound_off(&mut self, channel: usize) {
+        let channels = self.zones.controlled(channel);
+        for c in channels.clone() {
+            self.notes[c].all_sound_off();
+        }
+        for voice in self
+            .voices
+            .iter_mut()
+            .filter(|v| channels.contains(&v.channel))
+        {
+            voice.release();
+            voice.controls.damping.set_value(1.0);
+        }
+    }
+
+    /// Put the sustain pedal of `channel` down or, when it comes up, release the notes it was
+    /// sustaining.
+    pub fn sustain(&mut self, channel: usize, down: bool) {
+        for c in self.zones.controlled(channel) {
+            self.notes[c].sustain(down);
+        }
+        self.release_finished();
+    }
+
+    /// Put the sostenuto pedal of `channel` down, sustaining only the notes held at that moment,
+    /// or, when it comes up, release the notes it was sustaining.
+    pub fn sostenuto(&mut self, channel: usize, down: bool) {
+        for c in self.zones.controlled(channel) {
+            self.notes[c].sostenuto(down);
+        }
+        self.release_finished();
+    }
+
+    /// Put the soft pedal of `channel` down, or lift it. It only changes the notes that start
+    /// after it.
+    pub fn soft_pedal(&mut self, channel: usize, down: bool) {
+        for c in self.zones.controlled(channel) {
+            self.notes[c].soft(down);
+        }
+    }
+
+    /// Apply polyphonic aftertouch `pressure` (0 to 1) to every sounding voice playing `note`
+    /// from `channel`, or from any channel of the zone it manages.
+    pub fn note_pressure(&mut self, channel: usize, note: u8, pressure: f64) {
+        let channels = self.zones.controlled(channel);
+        for voice in self
+            .voices
+            .iter()
+            .filter(|v| v.sounding && channels.contains(&v.channel) && v.note == Some(note))
+        {
+            self.press(&voice.controls, pressure);
+        }
+    }
+
+    /// Apply channel aftertouch `pressure` (0 to 1) to every sounding voice of `channel`, and to
+    /// its notes started from now on.
+    pub fn channel_pressure(&mut self, channel: usize, pressure: f64) {
+        let channels = self.zones.controlled(channel);
+        for c in channels.clone() {
+            self.channels[c].pressure = pressure;
+        }
+        for voice in self
+            .voices
+            .iter()
+            .filter(|v| v.sounding && channels.contains(&v.channel))
+        {
+            self.press(&voice.controls, pressure);
+        }
+    }
+
+    /// Lift every pedal of `channel`, take its aftertouch and pitch bend back to rest and deselect
+    /// its parameter, as on a Reset All Controllers message. The bend range, the timbre and the
+    /// pluck position, like the other sound controllers, are kept.
+    pub fn reset_controllers(&mut self, channel: usize) {
+        self.sustain(channel, false);
+        self.sostenuto(channel, false);
+        self.soft_pedal(channel, false);
+        self.channel_pressure(channel, 0.0);
+        for c in self.zones.controlled(channel) {
+            self.channels[c].reset();
+        }
+        self.bend_voices(channel);
+    }
+
+    /// Switch to the instrument of the preset General MIDI `program` is played with (see
+    /// `program_preset()`), if it is one of the programs and isn't already playing. Its voices
+    /// replace the old ones, which are released. Returns the index of the program, to build the
+    /// synth for its voices (see `program_voices()`). Nothing is allocated, so this can be called
+    /// on the audio thread.
+    pub fn program_change(&mut self, program: u8) -> Option<usize> {
+        let name = program_preset(program)?;
+        if self.instrument().is_some_and(|i| i.name == name) {
+            return None;
+        }
+        let index = self
+            .programs
+            .iter()
+            .position(|p| p.instrument.name == name)?;
+        // the old voices ring out as the synth fades them, and are free when switched back to
+        for voice in &mut self.voices {
+            voice.release();
+            voice.note = None;
+        }
+        if let Some(playing) = self.program.replace(index) {
+            std::mem::swap(&mut self.voices, &mut self.programs[playing].voices);
+        }
+        std::mem::swap(&mut self.voices, &mut self.programs[index].voices);
+        Some(index)
+    }
+
+    /// The voices of program `index` (as returned by `program_change()`) and their string models.
+    pub fn program_voices(&self, index: usize) -> InstrumentVoices {
+        let program = &self.programs[index];
+        let voices = match self.program {
+            Some(playing) if playing == index => &self.voices,
+            _ => &program.voices,
+        };
+        let controls = voices.iter().map(|v| v.controls.clone()).collect();
+        (controls, program.instrument.string_models())
+    }
+
+    /// The voices of every program and their string models, by index.
+    pub fn programs(&self) -> Vec<InstrumentVoices> {
+        (0..self.programs.len())
+            .map(|index| self.program_voices(index))
+            .collect()
+    }
+
+    /// Move the bend wheel of `channel` to `bend` (0 to 16383), bending the voices playing its
+    /// notes, including those started later.
+    pub fn pitch_bend(&mut self, channel: usize, bend: u16) {
+        self.channels[channel].pitch_bend(bend);
+        self.bend_voices(channel);
+    }
+
+    /// Apply a value of the timbre controller of `channel` (CC 74), or of a controller that
+    /// selects, sets or steps a registered parameter: the bend range, or the MPE zone it manages.
+    pub fn controller(&mut self, channel: usize, control: u8, value: u8) {
+        match self.channels[channel].controller(control, value) {
+            Some(Change::BendRange) => match self.zones.manager(channel) {
+                // the member channels of a zone share their bend range
+                Some(manager) => {
+                    let range = self.channels[channel].bend_range();
+                    for c in self.zones.controlled(manager).filter(|&c| c != manager) {
+                        self.channels[c].set_bend_range(range);
+                    }
+                    self.bend_voices(manager);
+                }
+                None => self.bend_voices(channel),
+            },
+            Some(Change::Timbre) => {
+                let timbre = self.channels[channel].timbre;
+                let channels = self.zones.controlled(channel);
+                for c in channels.clone() {
+                    self.channels[c].timbre = timbre;
+                }
+                for voice in self.voices.iter().filter(|v| channels.contains(&v.channel)) {
+                    voice.controls.timbre.set_value(timbre);
+                }
+            }
+            Some(Change::Zone(members)) => self.configure_zone(channel, members),
+            None => {}
+        }
+    }
+
+    /// Give the MPE zone managed from `manager` (0 for the lower zone, 15 for the upper) `members`
+    /// member channels, or turn it off with 0, as on an MPE Configuration Message. The member
+    /// channels bend by up to `MPE_BEND_RANGE` semitones until a controller changes it, and the
+    /// channels that leave a zone go back to the bend range the allocator was set up with. Other
+    /// channels can't manage a zone, and are left alone.
+    pub fn configure_zone(&mut self, manager: usize, members: u8) {
+        let Some((members, left)) = self.zones.configure(manager, members) else {
+            return;
+        };
+        for c in left.into_iter().flatten() {
+            self.channels[c].set_bend_range(self.bend_range);
+            self.bend_voices(c);
+        }
+        for c in members {
+            self.channels[c].set_bend_range(MPE_BEND_RANGE);
+        }
+        self.bend_voices(manager);
+    }
+
+    /// Pluck every note of `channel` started from now on at `position`, as a fraction of the
+    /// string length from the bridge. Notes already sounding keep the position they were plucked
+    /// at.
+    pub fn pluck_position(&mut self, channel: usize, position: f64) {
+        for c in self.zones.controlled(channel) {
+            self.channels[c].pluck_position = position;
+        }
+    }
+
+    /// The bend of the notes of `channel`: its own, and that of the manager channel of its MPE
+    /// zone.
+    fn voice_bend(&self, channel: usize) -> f64 {
+        let zone = self
+            .zones
+            .manager(channel)
+            .map_or(1.0, |manager| self.channels[manager].bend_factor());
+        self.channels[channel].bend_factor() * zone
+    }
+
+    /// Write the bend to every voice playing the notes of `channel`, or of its zone if it manages
+    /// one.
+    fn bend_voices(&self, channel: usize) {
+        let channels = self.zones.controlled(channel);
+        for voice in self.voices.iter().filter(|v| channels.contains(&v.channel)) {
+            voice
+                .controls
+                .pitch_bend
+                .set_value(self.voice_bend(voice.channel));
+        }
+    }
+
+    /// Release every sounding voice whose note its channel's tracker has released.
+    fn release_finished(&mut self) {
+        let notes = &self.notes;
+        for voice in self.voices.iter_mut().filter(|v| v.sounding) {
+            let tracker = &notes[voice.channel];
+            if voice
+                .note
+                .is_none_or(|note| tracker.state(note) == NoteState::Released)
+            {
+                voice.release();
+            }
+        }
+    }
+
+    /// Write aftertouch `pressure` to the vibrato or damping of a voice, whichever `--aftertouch`
+    /// chose, taking the other back to rest.
+    fn press(&self, controls: &VoiceControls, pressure: f64) {
+        let (vibrato, damping) = match self.aftertouch {
+            Aftertouch::Vibrato => (pressure, 0.0),
+            Aftertouch::Damping => (0.0, pressure),
+        };
+        controls.vibrato.set_value(vibrato);
+        controls.damping.set_value(damping);
+    }
+
+    /// The instrument playing, if the voices are its strings.
+    fn instrument(&self) -> Option<&Instrument> {
+        match self.program {
+            Some(index) => Some(&self.programs[index].instrument),
+            None => self.instrument.as_ref(),
+        }
+    }
+
+    /// The fret voice `index` needs to play `note`, if it can play it at all. Voices that are not
+    /// strings of an instrument can play any note "open".
+    fn fret(&self, index: usize, note: u8) -> Option<u8> {
+        match self.instrument() {
+            Some(instrument) => instrument.fret(index, note),
+            None => Some(0),
+        }
+    }
+
+    /// Pick the voice for `note` from `channel`, returning its index and the fret it is played at.
+    fn choose_voice(&self, channel: usize, note: u8) -> Option<(usize, u8)> {
+        let reachable = || {
+            self.voices
+                .iter()
+                .enumerate()
+                .filter_map(move |(i, v)| Some((i, v, self.fret(i, note)?)))
+        };
+
+        if self.policy == StealPolicy::SameNote {
+            // in an MPE zone, the same note on another channel is played by another finger
+            if let Some((index, _, fret)) =
+                reachable().find(|(_, v, _)| v.note == Some(note) && v.channel == channel)
+            {
+                return Some((index, fret));
+            }
+        }
+
+        // released voices are always preferred over stealing a held or sustained one, and among
+        // them the string closest to open position is the most natural choice
+        if let Some((index, _, fret)) = reachable()
+            .filter(|(_, v, _)| !v.sounding)
+            .min_by_key(|(_, v, fret)| (*fret, v.started))
+        {
+            return Some((index, fret));
+        }
+
+        match self.policy {
+            StealPolicy::Quietest => reachable()
+                .min_by(|(_, a, _), (_, b, _)| {
+                    a.controls
+                        .level
+                        .value()
+                        .total_cmp(&b.controls.level.value())
+                })
+                .map(|(i, _, fret)| (i, fret)),
+            StealPolicy::Oldest | StealPolicy::SameNote => reachable()
+                .min_by_key(|(_, v, _)| v.started)
+                .map(|(i, _, fret)| (i, fret)),
+        }
+    }
+}
+
+/// Sums the voices built by `create_voice`, which is given each voice's index and controls, into a
+/// single mono mix bus. The mix is scaled by the inverse square root of the voice count to leave
+/// headroom for chords.
+pub fn create_mix<F>(voices: &[VoiceControls], mut create_voice: F) -> Box<dyn AudioUnit64>
+where
+    F: FnMut(usize, &VoiceControls) -> Box<dyn AudioUnit64>,
+{
+    let gain = (max(voices.len(), 1) as f64).sqrt().recip();
+    let mix = voices
+        .iter()
+        .enumerate()
+        .map(|(i, voice)| Net64::wrap(create_voice(i, voice)))
+        .reduce(|mix, voice| mix + voice)
+        .unwrap_or_else(|| Net64::wrap(Box::new(zero())));
+    Box::new(mix * gain)
+}
+
+/// Passes the control signal through, except for a single sample of -1.0 whenever the `trigger`
+/// changes. This lets `exciter()` see a release followed by a new attack when a held voice is
+/// re-plucked.
+#[derive(Clone)]
+pub struct Retrigger {
+    trigger: Shared<f64>,
+    last: f64,
+}
+
+impl AudioNode for Retrigger {
+    const ID: u64 = 1001;
+    type Sample = f64;
+    type Inputs = U1;
+    type Outputs = U1;
+    type Setting = ();
+
+    fn reset(&mut self, _sample_rate: Option<f64>) {
+        self.last = self.trigger.value();
+    }
+
+    #[inline]
+    fn tick(&mut self, input: &Frame<f64, U1>) -> Frame<f64, U1> {
+        let trigger = self.trigger.value();
+        if trigger != self.last {
+            self.last = trigger;
+            [-1.0].into()
+        } else {
+            [input[0]].into()
+        }
+    }
+}
+
+/// Retrigger the control signal whenever `trigger` changes.
+/// - Input 0: control
+/// - Output 0: control with a release sample inserted on each trigger
+pub fn retrigger(trigger: &Shared<f64>) -> An<Retrigger> {
+    An(Retrigger {
+        trigger: trigger.clone(),
+        last: trigger.value(),
+    })
+}
+
+/// Passes audio through while storing a peak level, decaying with a 50 ms half-life, in `level`.
+#[derive(Clone)]
+pub struct LevelMeter {
+    level: Shared<f64>,
+    value: f64,
+    decay: f64,
+}
+
+impl AudioNode for LevelMeter {
+    const ID: u64 = 1002;
+    type Sample = f64;
+    type Inputs = U1;
+    type Outputs = U1;
+    type Setting = ();
+
+    fn reset(&mut self, sample_rate: Option<f64>) {
+        if let Some(sample_rate) = sample_rate {
+            self.decay = 0.5_f64.powf(1.0 / (0.05 * sample_rate));
+        }
+        self.value = 0.0;
+        self.level.set_value(0.0);
+    }
+
+    #[inline]
+    fn tick(&mut self, input: &Frame<f64, U1>) -> Frame<f64, U1> {
+        self.value = max(abs(input[0]), self.value * self.decay);
+        self.level.set_value(self.value);
+        [input[0]].into()
+    }
+}
+
+/// Meter the signal into `level`.
+/// - Input 0: audio
+/// - Output 0: audio, unchanged
+pub fn level_meter(level: &Shared<f64>) -> An<LevelMeter> {
+    let mut node = LevelMeter {
+        level: level.clone(),
+        value: 0.0,
+        decay: 0.0,
+    };
+    node.reset(Some(DEFAULT_SR));
+    An(node)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // An allocator of two voices, both playing a note on channel 0: note 60 on voice 0, then note
+    // 62 on voice 1.
+    fn full_pool(policy: StealPolicy) -> VoiceAllocator {
+        let mut voices = VoiceAllocator::new(2, policy);
+        assert_eq!(voices.note_on(0, 60, 100), Some(0));
+        assert_eq!(voices.note_on(0, 62, 100), Some(1));
+        voices
+    }
+
+    // Whether each voice's note is still held or sustained.
+    fn sounding(voices: &VoiceAllocator) -> Vec<bool> {
+        voices
+            .controls()
+            .iter()
+            .map(|voice| voice.control.value() > 0.0)
+            .collect()
+    }
+
+    #[test]
+    fn oldest_steals_the_first_note() {
+        let mut voices = full_pool(StealPolicy::Oldest);
+        assert_eq!(voices.note_on(0, 64, 100), Some(0));
+        assert_eq!(voices.note_on(0, 65, 100), Some(1));
+    }
+
+    #[test]
+    fn quietest_steals_the_lowest_level() {
+        let mut voices = full_pool(StealPolicy::Quietest);
+        let controls = voices.controls();
+        controls[0].level.set_value(0.5);
+        controls[1].level.set_value(0.1);
+        assert_eq!(voices.note_on(0, 64, 100), Some(1));
+    }
+
+    #[test]
+    fn same_note_replucks_its_voice() {
+        let mut voices = full_pool(StealPolicy::SameNote);
+        assert_eq!(voices.note_on(0, 62, 100), Some(1));
+        assert_eq!(voices.controls()[1].trigger.value(), 2.0);
+        // the same note from another channel is another note, so the oldest voice is taken
+        assert_eq!(voices.note_on(1, 60, 100), Some(0));
+        assert_eq!(voices.note_on(0, 62, 100), Some(1));
+    }
+
+    #[test]
+    fn released_voices_are_taken_before_sounding_ones() {
+        for policy in [
+            StealPolicy::Oldest,
+            StealPolicy::Quietest,
+            StealPolicy::SameNote,
+        ] {
+            let mut voices = full_pool(policy);
+            voices.controls()[0].level.set_value(0.1);
+            voices.controls()[1].level.set_value(0.5);
+            voices.note_off(0, 62);
+            assert_eq!(voices.note_on(0, 64, 100), Some(1), "{policy:?}");
+        }
+    }
+
+    #[test]
+    fn note_off_matches_the_channel_and_the_note() {
+        let mut voices = VoiceAllocator::new(3, StealPolicy::Oldest);
+        voices.note_on(0, 60, 100);
+        voices.note_on(1, 60, 100);
+        voices.note_on(0, 62, 100);
+        voices.note_off(0, 64);
+        voices.note_off(2, 60);
+        assert_eq!(sounding(&voices), [true, true, true]);
+        voices.note_off(1, 60);
+        assert_eq!(sounding(&voices), [true, false, true]);
+        voices.note_off(0, 60);
+        assert_eq!(sounding(&voices), [false, false, true]);
+    }
+
+    #[test]
+    fn pluck_position_applies_to_its_channel() {
+        let mut voices = VoiceAllocator::new(3, StealPolicy::Oldest);
+        voices.pluck_position(1, 0.4);
+        voices.note_on(0, 60, 100);
+        voices.note_on(1, 60, 100);
+        let positions: Vec<f64> = voices
+            .controls()
+            .iter()
+            .map(|voice| voice.pluck_position.value())
+            .collect();
+        assert_eq!(
+            positions,
+            [DEFAULT_PLUCK_POSITION, 0.4, DEFAULT_PLUCK_POSITION]
+        );
+
+        // on the manager channel of an MPE zone, it applies to the notes of every member channel
+        voices.configure_zone(0, 15);
+        voices.pluck_position(0, 0.1);
+        voices.note_on(5, 64, 100);
+        assert_eq!(voices.controls()[2].pluck_position.value(), 0.1);
+    }
+
+    #[test]
+    fn note_pressure_on_a_manager_reaches_its_members() {
+        // the note pressed is shaken on whichever member channel it was played, but not the same
+        // note played outside the zone
+        let mut voices = VoiceAllocator::new(3, StealPolicy::Oldest);
+        voices.configure_zone(0, 3);
+        voices.note_on(1, 60, 100);
+        voices.note_on(2, 62, 100);
+        voices.note_on(5, 60, 100);
+        voices.note_pressure(0, 60, 0.5);
+        let vibrato: Vec<f64> = voices
+            .controls()
+            .iter()
+            .map(|voice| voice.vibrato.value())
+            .collect();
+        assert_eq!(vibrato, [0.5, 0.0, 0.0]);
+    }
+}
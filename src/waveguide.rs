@@ -0,0 +1,372 @@
+//! Fractional delay line for the waveguide loop.
+//!
+//! The pitch of the string is set by the length of its delay loop, which is almost never a whole
+//! number of samples: at 48 kHz, rounding E6 (1318.5 Hz, 36.4 samples) to 36 samples puts it 19
+//! cents sharp. `FractionalDelay` interpolates between samples so the loop can take any length,
+//! with a choice of interpolators that trade accuracy against brightness and cost.
+//!
+//! The filters in the loop delay the signal too, so `LoopTuning` works out how long the delay
+//! line itself must be for each fundamental. The interpolators only delay the high partials by
+//! the fraction they read between samples approximately, so it also corrects for the phase delay
+//! of the interpolator at the fundamental.
+
+use crate::dispersion;
+use crate::loss::LossFilter;
+use crate::string::StringModel;
+use fundsp::hacker::*;
+use rustfft::num_complex::Complex64;
+
+// How many times `LoopTuning` corrects the length of the delay line for the phase delay of the
+// interpolator, each correction taking the error down by orders of magnitude.
+static INTERPOLATION_CORRECTIONS: usize = 3;
+
+/// How `FractionalDelay` reads between samples.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Interpolation {
+    /// Straight line between the two nearest samples. Cheap, but acts as a lowpass filter that
+    /// dulls the string more when the delay falls halfway between samples.
+    Linear,
+    /// Third-order Lagrange polynomial through the four nearest samples.
+    Lagrange3,
+    /// Fifth-order Lagrange polynomial through the six nearest samples. The flattest response.
+    Lagrange5,
+    /// First-order Thiran allpass filter. Passes every frequency at full level, so the string
+    /// loses no brightness, but clicks when the delay jumps (such as on a new note).
+    Thiran,
+}
+
+impl Interpolation {
+    /// Shortest delay (samples) the interpolator can produce without reading samples that
+    /// haven't arrived yet.
+    fn min_delay(&self) -> f64 {
+        match self {
+            Interpolation::Linear => 0.0,
+            Interpolation::Lagrange3 => 1.0,
+            Interpolation::Lagrange5 => 2.0,
+            Interpolation::Thiran => 0.5,
+        }
+    }
+
+    /// Phase delay (samples) at `omega` (radians per sample) of reading `delay` samples back.
+    fn phase_delay(&self, delay: f64, omega: f64) -> f64 {
+        // the response to a complex tone, turned back by the delay it is meant to have, so its
+        // angle is only the (small) error
+        let re = |i: usize| (omega * (delay - i as f64)).cos();
+        let im = |i: usize| (omega * (delay - i as f64)).sin();
+        let response = match self {
+            Interpolation::Linear => Complex64::new(linear(delay, re), linear(delay, im)),
+            Interpolation::Lagrange3 => {
+                Complex64::new(lagrange::<4>(delay, 1.0, re), lagrange::<4>(delay, 1.0, im))
+            }
+            Interpolation::Lagrange5 => {
+                Complex64::new(lagrange::<6>(delay, 2.0, re), lagrange::<6>(delay, 2.0, im))
+            }
+            Interpolation::Thiran => {
+                let (whole, coefficient) = thiran(delay);
+                let z = Complex64::from_polar(1.0, -omega);
+                Complex64::from_polar(1.0, omega * (delay - whole)) * (coefficient + z)
+                    / (1.0 + coefficient * z)
+            }
+        };
+        delay - response.arg() / omega
+    }
+}
+
+/// A variable delay line with fractional-sample interpolation.
+#[derive(Clone)]
+pub struct FractionalDelay {
+    interpolation: Interpolation,
+    min_delay: f64,
+    max_delay: f64,
+    sample_rate: f64,
+    buffer: Vec<f64>,
+    mask: usize,
+    write: usize,
+    // input and output of the Thiran allpass on the previous sample
+    allpass_in: f64,
+    allpass_out: f64,
+}
+
+impl FractionalDelay {
+    /// Read the buffer `delay` samples (at least `interpolation.min_delay()`) before the newest
+    /// sample.
+    #[inline]
+    fn read(&mut self, delay: f64) -> f64 {
+        let at = |i: usize| self.buffer[(self.write.wrapping_sub(i)) & self.mask];
+        match self.interpolation {
+            Interpolation::Linear => linear(delay, at),
+            Interpolation::Lagrange3 => lagrange::<4>(delay, 1.0, at),
+            Interpolation::Lagrange5 => lagrange::<6>(delay, 2.0, at),
+            Interpolation::Thiran => {
+                let (whole, coefficient) = thiran(delay);
+                let input = at(whole as usize);
+                let output = coefficient * input + self.allpass_in - coefficient * self.allpass_out;
+                self.allpass_in = input;
+                self.allpass_out = output;
+                output
+            }
+        }
+    }
+}
+
+/// Delay (samples) at `freq` of the `loss` filter and the dispersion of `string`, which a
+/// waveguide takes off the length of its delay lines to stay in tune. Every line adds a sample of
+/// its own too, as it is read before it is written.
+pub(crate) fn filter_delay(
+    loss: &LossFilter,
+    string: &StringModel,
+    freq: f64,
+    sample_rate: f64,
+) -> f64 {
+    let pole = loss.design(string, freq, sample_rate).pole;
+    loss.phase_delay(pole, freq, sample_rate) + dispersion::design(string, freq, sample_rate).delay
+}
+
+/// Linear interpolation between the two samples around `delay`.
+#[inline]
+pub(crate) fn linear(delay: f64, at: impl Fn(usize) -> f64) -> f64 {
+    let whole = delay.floor();
+    let frac = delay - whole;
+    let i = whole as usize;
+    at(i) * (1.0 - frac) + at(i + 1) * frac
+}
+
+/// Splits `delay` into a whole number of samples and the coefficient of a first-order Thiran
+/// allpass delaying the remaining 0.5 to 1.5 samples.
+#[inline]
+fn thiran(delay: f64) -> (f64, f64) {
+    let whole = (delay - 0.5).floor();
+    let frac = delay - whole;
+    (whole, (1.0 - frac) / (1.0 + frac))
+}
+
+/// Lagrange interpolation through the `N` samples around `delay`. The first sample used is
+/// `floor(delay - offset)` samples back, which centres `delay` among the `N` points.
+#[inline]
+pub(crate) fn lagrange<const N: usize>(delay: f64, offset: f64, at: impl Fn(usize) -> f64) -> f64 {
+    let first = (delay - offset).floor();
+    let d = delay - first;
+    let mut sum = 0.0;
+    for k in 0..N {
+        let mut weight = 1.0;
+        for j in 0..N {
+            if j != k {
+                weight *= (d - j as f64) / (k as f64 - j as f64);
+            }
+        }
+        sum += weight * at(first as usize + k);
+    }
+    sum
+}
+
+impl AudioNode for FractionalDelay {
+    const ID: u64 = 1003;
+    type Sample = f64;
+    type Inputs = U2;
+    type Outputs = U1;
+    type Setting = ();
+
+    fn reset(&mut self, sample_rate: Option<f64>) {
+        if let Some(sample_rate) = sample_rate {
+            self.sample_rate = sample_rate;
+            // room for the longest delay plus the extra points read by the interpolator
+            let length = (self.max_delay * sample_rate).ceil() as usize + 8;
+            self.buffer = vec![0.0; length.next_power_of_two()];
+            self.mask = self.buffer.len() - 1;
+        }
+        self.buffer.fill(0.0);
+        self.write = 0;
+        self.allpass_in = 0.0;
+        self.allpass_out = 0.0;
+    }
+
+    #[inline]
+    fn tick(&mut self, input: &Frame<f64, U2>) -> Frame<f64, U1> {
+        self.write = (self.write + 1) & self.mask;
+        self.buffer[self.write] = input[0];
+        let delay = clamp(self.min_delay, self.max_delay, input[1]) * self.sample_rate;
+        let delay = max(delay, self.interpolation.min_delay());
+        [self.read(delay)].into()
+    }
+}
+
+/// Variable delay line with fractional-sample `interpolation`, like `tap()`. Delays shorter than
+/// the interpolator can produce are lengthened to its minimum.
+/// - Input 0: audio
+/// - Input 1: delay time (s), clamped to `min_delay..max_delay`
+/// - Output 0: delayed audio
+pub fn fractional_delay(
+    interpolation: Interpolation,
+    min_delay: f64,
+    max_delay: f64,
+) -> An<FractionalDelay> {
+    let mut node = FractionalDelay {
+        interpolation,
+        min_delay,
+        max_delay,
+        sample_rate: DEFAULT_SR,
+        buffer: vec![],
+        mask: 0,
+        write: 0,
+        allpass_in: 0.0,
+        allpass_out: 0.0,
+    };
+    node.reset(Some(DEFAULT_SR));
+    An(node)
+}
+
+/// Converts a fundamental into the delay time of the waveguide `fractional_delay()`, refitted
+/// whenever the fundamental changes, as the dispersion is too costly to design on every sample.
+#[derive(Clone)]
+pub struct LoopTuning {
+    loss: LossFilter,
+    string: StringModel,
+    interpolation: Interpolation,
+    min_delay: f64,
+    max_delay: f64,
+    sample_rate: f64,
+    freq: f64,
+    delay: f64,
+}
+
+impl LoopTuning {
+    /// One period of `freq_hz` is a full trip around the loop, and `feedback2()` already
+    /// contributes a single sample of that, so it is subtracted here along with the delay of the
+    /// loss filter and the dispersion at the fundamental. The delay line is then lengthened or
+    /// shortened until the interpolator delays the fundamental by the rest.
+    fn loop_delay(&self, freq_hz: f64) -> f64 {
+        let filter_delay = filter_delay(&self.loss, &self.string, freq_hz, self.sample_rate);
+        let target = clamp(
+            self.min_delay,
+            self.max_delay,
+            1.0 / freq_hz - (1.0 + filter_delay) / self.sample_rate,
+        ) * self.sample_rate;
+        let omega = TAU * freq_hz / self.sample_rate;
+        let mut delay = max(target, self.interpolation.min_delay());
+        for _ in 0..INTERPOLATION_CORRECTIONS {
+            let error = self.interpolation.phase_delay(delay, omega) - target;
+            delay = max(delay - error, self.interpolation.min_delay());
+        }
+        delay / self.sample_rate
+    }
+}
+
+impl AudioNode for LoopTuning {
+    const ID: u64 = 1009;
+    type Sample = f64;
+    type Inputs = U1;
+    type Outputs = U1;
+    type Setting = ();
+
+    fn reset(&mut self, sample_rate: Option<f64>) {
+        if let Some(sample_rate) = sample_rate {
+            self.sample_rate = sample_rate;
+            // retune on the next sample
+            self.freq = 0.0;
+        }
+    }
+
+    #[inline]
+    fn tick(&mut self, input: &Frame<f64, U1>) -> Frame<f64, U1> {
+        if input[0] != self.freq {
+            self.freq = input[0];
+            self.delay = self.loop_delay(self.freq);
+        }
+        [self.delay].into()
+    }
+}
+
+/// Delay time of the waveguide loop of `string`, with its `loss` filter and dispersion, clamped
+/// to `min_delay..max_delay`, for a `fractional_delay()` with `interpolation`.
+/// - Input 0: fundamental (Hz)
+/// - Output 0: delay time (s) for `fractional_delay()`
+pub fn loop_tuning(
+    loss: LossFilter,
+    string: &StringModel,
+    interpolation: Interpolation,
+    min_delay: f64,
+    max_delay: f64,
+) -> An<LoopTuning> {
+    let mut node = LoopTuning {
+        loss,
+        string: *string,
+        interpolation,
+        min_delay,
+        max_delay,
+        sample_rate: DEFAULT_SR,
+        freq: 0.0,
+        delay: min_delay,
+    };
+    node.reset(Some(DEFAULT_SR));
+    An(node)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static INTERPOLATIONS: [Interpolation; 4] = [
+        Interpolation::Linear,
+        Interpolation::Lagrange3,
+        Interpolation::Lagrange5,
+        Interpolation::Thiran,
+    ];
+
+    // How many samples `delayed_sine()` lets the delay line settle for.
+    static SETTLE: usize = 400;
+
+    // Reads a sine of `omega` radians per sample through a `fractional_delay()` of `delay`
+    // samples, returning the sample index and output once it has settled.
+    fn delayed_sine(interpolation: Interpolation, delay: f64, omega: f64) -> Vec<(f64, f64)> {
+        let sample_rate = 44100.0;
+        let mut line = fractional_delay(interpolation, 0.0, 0.01);
+        line.reset(Some(sample_rate));
+        (0..SETTLE + 1600)
+            .map(|i| {
+                let input = (omega * i as f64).sin();
+                (i as f64, line.tick(&[input, delay / sample_rate].into())[0])
+            })
+            .skip(SETTLE)
+            .collect()
+    }
+
+    #[test]
+    fn every_interpolator_reads_between_samples() {
+        // a slow sine comes out 10.3 samples late
+        let omega = TAU / 100.0;
+        for interpolation in INTERPOLATIONS {
+            for (i, output) in delayed_sine(interpolation, 10.3, omega) {
+                let expected = (omega * (i - 10.3)).sin();
+                assert!(
+                    (output - expected).abs() < 1e-3,
+                    "{interpolation:?} read {output} instead of {expected}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn phase_delay_matches_the_interpolator() {
+        // at 16 samples per period the interpolators stray from the delay they read, by as much
+        // as `phase_delay()` expects
+        let omega = TAU / 16.0;
+        for interpolation in INTERPOLATIONS {
+            for delay in [10.1, 10.3, 10.5, 10.8] {
+                // the angle of the output against a sine delayed by exactly `delay`
+                let (sin, cos) = delayed_sine(interpolation, delay, omega).iter().fold(
+                    (0.0, 0.0),
+                    |(sin, cos), (i, output)| {
+                        let phase = omega * (i - delay);
+                        (sin + output * phase.sin(), cos + output * phase.cos())
+                    },
+                );
+                let measured = delay + (-cos).atan2(sin) / omega;
+                let expected = interpolation.phase_delay(delay, omega);
+                assert!(
+                    (measured - expected).abs() < 1e-6,
+                    "{interpolation:?} delays {delay} by {measured}, not {expected}"
+                );
+            }
+        }
+    }
+}